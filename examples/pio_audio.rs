@@ -0,0 +1,620 @@
+#![no_std]
+#![no_main]
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::blocking::delay::DelayMs;
+use hal::dma::DMAExt;
+use hal::multicore::{Multicore, Stack};
+use hal::pac;
+use hal::pac::interrupt;
+use hal::pio::{PIOExt, PioIRQ};
+use hal::Clock;
+use hal::Sio;
+use panic_halt as _;
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+use rp2040_hal as hal;
+use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::prelude::{UsbDeviceBuilder, UsbVidPid};
+
+use pico_i2s::core1::{Command, SampleRing};
+use pico_i2s::i2s::{FifoJoin, I2sOutput, I2sOutputBuilder, I2sPins};
+use pico_i2s::input::I2sInput;
+use pico_i2s::irq::IrqFeed;
+use pico_i2s::pwm::{PwmChannel, PwmOutput};
+use pico_i2s::rates::{check_all_divisors, BckRatio, SampleFrequency};
+#[cfg(feature = "defmt")]
+use pico_i2s::rates::frequency_accuracy_report;
+use pico_i2s::sample::{cast_from_u32_as_i32, BitDepth};
+#[cfg(feature = "defmt")]
+use pico_i2s::sample::{dump_wire_words, mean_code};
+use pico_i2s::uac2::Uac2Speaker;
+use pico_i2s::synth::{
+    generate_stereo, Interpolation, SineOscillator, Waveform, Wavetable, AMPLITUDE, SINE_FRAMES, TABLE_SIZE,
+};
+#[cfg(feature = "defmt")]
+use pico_i2s::synth::{
+    generate_sine_wave_looped, generate_square_wave, generate_triangle_wave, FREQUENCY, SAMPLE_RATE, SINE_TABLE,
+};
+use pico_i2s::wav::WavClip;
+
+/// The linker will place this boot block at the start of our program image. We
+/// need this to help the ROM bootloader get our code up and running.
+/// Note: This boot block is not necessary when using a rp-hal based BSP
+/// as the BSPs already perform this step.
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
+
+// constants
+const XTAL_FREQ_HZ: u32 = 12_000_000u32;
+// Set to false on boards where gpio25 is not a status LED
+const STATUS_LED_ENABLED: bool = true;
+// Set to true to run the button retune demo in `main` instead of the fixed sine table
+const BUTTON_RETUNE_DEMO: bool = false;
+// Tones the retune demo steps through, one per button press
+const RETUNE_FREQUENCIES: [f32; 4] = [220.0, 440.0, 880.0, 1760.0];
+// How long the button has to read the same level before a press counts
+const DEBOUNCE_MS: u64 = 20;
+// Set to true to run as a USB speaker playing what the host streams instead of the test tone
+const USB_SPEAKER_DEMO: bool = false;
+// Set to true to loop the chime stored in flash instead of the test tone
+const WAV_CLIP_DEMO: bool = false;
+// 250ms of a two tone chime, mono 24 bit PCM at 48khz
+static CHIME_WAV: &[u8] = include_bytes!("chime.wav");
+// Set to true to keep the fifo fed from the PIO interrupt and sleep with `wfi` in between
+const IRQ_FEED_DEMO: bool = false;
+// Ring between the main loop and the interrupt handler, 512 frames
+const IRQ_RING_WORDS: usize = 1024;
+// Set to true to feed the fifo from core1, with core0 only producing the tone
+const CORE1_FEED_DEMO: bool = false;
+// Ring between the two cores, 512 frames
+const CORE1_RING_WORDS: usize = 1024;
+// Set to true to generate the tone on core1, with core0 retuning and muting it from the button
+const CORE1_SYNTH_DEMO: bool = false;
+// How long the button has to be held for a press to toggle mute instead of retuning
+const LONG_PRESS_MS: u64 = 1000;
+// Set to true to play a tone through PWM on gpio0, for boards without the DAC
+const PWM_FALLBACK_DEMO: bool = false;
+// Set to true to record from an I2S ADC on gpio2-4 and play each recording back
+const RECORD_PLAYBACK_DEMO: bool = false;
+// One recording, 8192 frames or 170ms at 48khz
+const RECORD_WORDS: usize = 16384;
+// Set to true to read the tone back off the output's data pin and light the LED while it matches
+const LOOPBACK_DEMO: bool = false;
+// Frames sent that the loopback demo looks for what comes back among, more than the fifos hold
+const LOOPBACK_HISTORY: usize = 16;
+// Set to false to feed the tx fifo from the CPU instead of the DMA loop
+const DMA_PLAYBACK: bool = true;
+// Half the period of the LED blink while the DMA loop plays
+const BLINK_HALF_PERIOD_US: u64 = 500_000;
+// Sample rate for each setting of the two rate jumpers (gpio13 is bit 0, gpio14 bit 1)
+const RATE_JUMPER_FREQUENCIES: [SampleFrequency; 4] = [
+    SampleFrequency::Freq192khz,
+    SampleFrequency::Freq48khz,
+    SampleFrequency::Freq44_1khz,
+    SampleFrequency::Freq96khz,
+];
+// Rate used instead of the jumpers' when the system clock can not reach every preset, one any
+// clock the example runs at does reach
+const FALLBACK_FREQUENCY: SampleFrequency = SampleFrequency::Freq48khz;
+// Tones the generator DC check runs, each a whole number of periods in a `TABLE_SIZE` table
+#[cfg(feature = "defmt")]
+const DC_CHECK_FREQUENCIES: [f32; 3] = [300.0, 1000.0, 4800.0];
+// Largest mean, in 24 bit codes, a clean tone over whole periods may show before it counts as DC
+#[cfg(feature = "defmt")]
+const DC_CHECK_LIMIT_CODES: f32 = 1.0;
+
+/// # Purpose
+/// Reads the sample rate selected by two jumpers to ground on pulled up inputs. A fitted jumper
+/// reads low and counts as a set bit, so with no jumpers fitted the output runs at
+/// `RATE_JUMPER_FREQUENCIES[0]`. A pin that fails to read counts as no jumper.
+fn read_rate_jumpers<A: InputPin, B: InputPin>(bit0: &A, bit1: &B) -> SampleFrequency {
+    let bit0 = bit0.is_low().unwrap_or(false) as usize;
+    let bit1 = bit1.is_low().unwrap_or(false) as usize;
+    RATE_JUMPER_FREQUENCIES[bit1 << 1 | bit0]
+}
+
+// Core1's stack for `CORE1_FEED_DEMO` and `CORE1_SYNTH_DEMO`
+static mut CORE1_STACK: Stack<4096> = Stack::new();
+
+// The feed shared with `PIO0_IRQ_0`, `None` until `irq_feed_demo` starts it
+static IRQ_FEED: Mutex<RefCell<Option<IrqFeed<pac::PIO0, IRQ_RING_WORDS>>>> = Mutex::new(RefCell::new(None));
+
+#[interrupt]
+fn PIO0_IRQ_0() {
+    critical_section::with(|cs| {
+        if let Some(feed) = IRQ_FEED.borrow_ref_mut(cs).as_mut() {
+            feed.on_interrupt();
+        }
+    });
+}
+
+/// # Purpose
+/// Plays the sine from `SINE_FRAMES` through an `IrqFeed`: the TX-not-full interrupt moves words
+/// from the ring into the fifo and the main loop only tops the ring up before sleeping with `wfi`.
+/// Once a second it reports the share of time spent asleep, which is the CPU freed compared to
+/// spinning in `write_sample`, where it is always 0%.
+fn irq_feed_demo(i2s: &mut I2sOutput<pac::PIO0>, timer: &hal::Timer) -> ! {
+    let mut feed = i2s.start_irq_feed::<IRQ_RING_WORDS>(PioIRQ::Irq0);
+    // refill once a quarter of the ring is left, in batches of three quarters of it
+    feed.set_refill_threshold(IRQ_RING_WORDS / 2 / 4);
+    critical_section::with(|cs| IRQ_FEED.borrow_ref_mut(cs).replace(feed));
+    // SAFETY: the handler only touches the feed through the mutex
+    unsafe { pac::NVIC::unmask(pac::Interrupt::PIO0_IRQ_0) };
+
+    let mut position = 0;
+    let mut asleep_us = 0_u64;
+    let mut window_start_us = timer.get_counter().ticks();
+    loop {
+        critical_section::with(|cs| {
+            if let Some(feed) = IRQ_FEED.borrow_ref_mut(cs).as_mut().filter(|feed| feed.needs_refill()) {
+                position += feed.push(&SINE_FRAMES[position..]);
+                if position == SINE_FRAMES.len() {
+                    position = 0;
+                }
+            }
+        });
+
+        let before_us = timer.get_counter().ticks();
+        cortex_m::asm::wfi();
+        let now_us = timer.get_counter().ticks();
+        asleep_us += now_us - before_us;
+
+        if now_us - window_start_us >= 1_000_000 {
+            let _asleep_percent = asleep_us * 100 / (now_us - window_start_us);
+            #[cfg(feature = "defmt")]
+            defmt::info!("asleep {}% of the time", _asleep_percent);
+            asleep_us = 0;
+            window_start_us = now_us;
+        }
+    }
+}
+
+/// # Purpose
+/// Core1's side of `CORE1_SYNTH_DEMO`: owns the output and keeps the fifo fed with a sine from a
+/// `Wavetable`, applying the commands core0 sends over the inter-core fifo between samples.
+fn core1_synth(mut i2s: I2sOutput<pac::PIO0>) -> ! {
+    // SAFETY: core1 only uses its own end of the SIO fifo
+    let pac = unsafe { pac::Peripherals::steal() };
+    let mut sio = Sio::new(pac.SIO);
+
+    let mut oscillator = Wavetable::<1024>::from_waveform(Waveform::Sine, AMPLITUDE);
+    oscillator.set_interpolation(Interpolation::Linear);
+    oscillator.set_frequency(RETUNE_FREQUENCIES[0], i2s.lrck_freq());
+    loop {
+        while !i2s.is_full() {
+            let sample = oscillator.next_sample();
+            i2s.write_stereo(sample, sample);
+        }
+        while let Some(word) = sio.fifo.read() {
+            match Command::from_word(word) {
+                Some(Command::SetTone { hz }) => oscillator.set_frequency(hz as f32, i2s.lrck_freq()),
+                Some(Command::Mute) => i2s.mute(),
+                Some(Command::Unmute) => i2s.unmute(),
+                None => {}
+            }
+        }
+    }
+}
+
+/// # Purpose
+/// Core0's side of `CORE1_SYNTH_DEMO`: hands the output to core1 and turns presses of `button`
+/// (active low, pulled up, debounced like `button_retune_demo`) into commands for it. A short
+/// press steps the tone through `RETUNE_FREQUENCIES`, a press held for `LONG_PRESS_MS` toggles
+/// mute. Core0 is left free for anything else between polls.
+fn core1_synth_demo<B: InputPin>(
+    i2s: I2sOutput<pac::PIO0>,
+    psm: &mut pac::PSM,
+    ppb: &mut pac::PPB,
+    fifo: &mut hal::sio::SioFifo,
+    button: &mut B,
+    timer: &hal::Timer,
+) -> ! {
+    {
+        let mut multicore = Multicore::new(psm, ppb, fifo);
+        let cores = multicore.cores();
+        // SAFETY: the stack is only ever handed to core1, and only once
+        let stack = unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK.mem) };
+        cores[1].spawn(stack, move || core1_synth(i2s)).unwrap();
+    }
+
+    let mut index = 0;
+    let mut muted = false;
+    let mut stable_pressed = false;
+    let mut last_pressed = false;
+    let mut last_change_us = 0_u64;
+    let mut pressed_at_us = 0_u64;
+    loop {
+        let now_us = timer.get_counter().ticks();
+        let pressed = button.is_low().unwrap_or(false);
+        if pressed != last_pressed {
+            last_pressed = pressed;
+            last_change_us = now_us;
+        } else if pressed != stable_pressed && now_us - last_change_us >= DEBOUNCE_MS * 1000 {
+            stable_pressed = pressed;
+            if pressed {
+                pressed_at_us = now_us;
+            } else if now_us - pressed_at_us >= LONG_PRESS_MS * 1000 {
+                muted = !muted;
+                fifo.write_blocking(if muted { Command::Mute } else { Command::Unmute }.to_word());
+            } else {
+                index = (index + 1) % RETUNE_FREQUENCIES.len();
+                fifo.write_blocking(Command::SetTone { hz: RETUNE_FREQUENCIES[index] as u32 }.to_word());
+            }
+        }
+    }
+}
+
+/// # Purpose
+/// Checks the tone generators for a DC offset at boot: `SINE_TABLE` and, at each of
+/// `DC_CHECK_FREQUENCIES`, a sine, square and triangle filled into `scratch`, logging the mean of
+/// each and warning about any over `DC_CHECK_LIMIT_CODES`. The symmetric waveforms average to
+/// zero over whole periods, so anything more is an asymmetric or broken generator.
+#[cfg(feature = "defmt")]
+fn check_generator_dc(scratch: &mut [u32]) {
+    log_dc("SINE_TABLE", FREQUENCY, &SINE_TABLE);
+    for requested_hz in DC_CHECK_FREQUENCIES {
+        let hz = generate_sine_wave_looped(scratch, requested_hz, SAMPLE_RATE, AMPLITUDE);
+        log_dc("sine", hz, scratch);
+        generate_square_wave(scratch, hz, SAMPLE_RATE, AMPLITUDE);
+        log_dc("square", hz, scratch);
+        generate_triangle_wave(scratch, hz, SAMPLE_RATE, AMPLITUDE);
+        log_dc("triangle", hz, scratch);
+    }
+}
+
+/// # Purpose
+/// Logs the mean of one generated table for `check_generator_dc`.
+#[cfg(feature = "defmt")]
+fn log_dc(name: &str, hz: f32, words: &[u32]) {
+    let mean = mean_code(words, BitDepth::Bits24);
+    if (-DC_CHECK_LIMIT_CODES..=DC_CHECK_LIMIT_CODES).contains(&mean) {
+        defmt::info!("{} at {} Hz, mean {} codes", name, hz, mean);
+    } else {
+        defmt::warn!("{} at {} Hz has a DC offset of {} codes", name, hz, mean);
+    }
+}
+
+/// # Purpose
+/// Interactive reference for runtime retuning: plays a sine from a `Wavetable` and steps it through
+/// `RETUNE_FREQUENCIES` each time `button` (active low, pulled up) is pressed. Only the phase step of
+/// the oscillator changes on a press, so the tone retunes without a click. The button is debounced
+/// by requiring the same level for `DEBOUNCE_MS` before a change is accepted. The LED shows the
+/// current index as a group of index + 1 flashes every two seconds, computed from the timer so it
+/// never stalls the audio.
+fn button_retune_demo<P: PIOExt, B: InputPin, L: OutputPin>(
+    i2s: &mut I2sOutput<P>,
+    button: &mut B,
+    mut led: Option<&mut L>,
+    timer: &hal::Timer,
+) -> ! {
+    let mut oscillator = Wavetable::<1024>::from_waveform(Waveform::Sine, AMPLITUDE);
+    oscillator.set_interpolation(Interpolation::Linear);
+    let mut index = 0;
+    oscillator.set_frequency(RETUNE_FREQUENCIES[index], i2s.lrck_freq());
+
+    let mut stable_pressed = false;
+    let mut last_pressed = false;
+    let mut last_change_us = 0_u64;
+
+    loop {
+        // keep the fifo topped up between button polls
+        while !i2s.is_full() {
+            let sample = oscillator.next_sample();
+            i2s.write_stereo(sample, sample);
+        }
+
+        let now_us = timer.get_counter().ticks();
+        let pressed = button.is_low().unwrap_or(false);
+        if pressed != last_pressed {
+            last_pressed = pressed;
+            last_change_us = now_us;
+        } else if pressed != stable_pressed && now_us - last_change_us >= DEBOUNCE_MS * 1000 {
+            stable_pressed = pressed;
+            if pressed {
+                index = (index + 1) % RETUNE_FREQUENCIES.len();
+                oscillator.set_frequency(RETUNE_FREQUENCIES[index], i2s.lrck_freq());
+            }
+        }
+
+        if let Some(led) = led.as_mut() {
+            // 200ms slots in a 2s cycle, on in the even slots of the first 2 * (index + 1)
+            let slot = (now_us % 2_000_000) / 200_000;
+            let lit = slot < 2 * (index as u64 + 1) && slot & 1 == 0;
+            let _ = if lit { led.set_high() } else { led.set_low() };
+        }
+    }
+}
+
+// Entry point to our bare-metal application.
+#[rp2040_hal::entry]
+fn main() -> ! {
+    let mut pac = pac::Peripherals::take().unwrap();
+
+    let mut sio = Sio::new(pac.SIO);
+    let pins = hal::gpio::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    );
+
+    // The status LED is a Pico board detail and not part of the I2S output, boards where gpio25
+    // is not an LED can turn it off with `STATUS_LED_ENABLED`.
+    let mut led_pin = if STATUS_LED_ENABLED {
+        Some(pins.gpio25.into_push_pull_output())
+    } else {
+        None
+    };
+
+    // The Pico Audio Pack's I2S lines, handed to PIO0. Boards that route I2S elsewhere only change
+    // these three, LRCK has to stay the pin right after BCK.
+    let i2s_pins = I2sPins::from_pio0(
+        pins.gpio9.into_function(),
+        pins.gpio10.into_function(),
+        pins.gpio11.into_function(),
+    )
+    .unwrap();
+
+    // XSMT on the Pico Audio Pack, held low so the DAC stays muted until the clocks have settled
+    let mut mute_pin = pins.gpio22.into_push_pull_output().into_dyn_pin();
+    mute_pin.set_low().unwrap();
+
+    let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    // the output's program leaves no room on PIO0 for the input demos, they run on PIO1
+    let (mut pio1, _, _, sm2, _) = pac.PIO1.split(&mut pac.RESETS);
+    // The sample rate comes from the rate jumpers, read once at reset. `I2sOutput::set_sample_frequency`
+    // switches it later without rebuilding the output.
+    let rate_bit0 = pins.gpio13.into_pull_up_input();
+    let rate_bit1 = pins.gpio14.into_pull_up_input();
+    let target_lrck_freq = read_rate_jumpers(&rate_bit0, &rate_bit1);
+    let target_bck_ratio = BckRatio::Auto;
+    let fifo_join = FifoJoin::Separate;
+
+    // `init_clocks_and_plls` runs PLL_USB at the 48MHz the USB controller needs, independent of
+    // PLL_SYS and so of the audio clocks, which is all the USB speaker demo needs
+
+    // hard panned test tone, the sine on the left channel and silence on the right
+    let mut left = [0; TABLE_SIZE];
+    let mut right = [0; TABLE_SIZE];
+    #[cfg(feature = "defmt")]
+    check_generator_dc(&mut left);
+    generate_stereo(&mut left, &mut right);
+    #[cfg(feature = "defmt")]
+    dump_wire_words(&left, 16);
+    if let Some(led) = led_pin.as_mut() {
+        led.set_high().unwrap();
+    }
+
+    // Set up the watchdog driver - needed by the clock setup code
+    let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
+    // Configure the clocks
+    let clocks = hal::clocks::init_clocks_and_plls(
+        XTAL_FREQ_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let mut timer = rp2040_hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+
+    // Initialize and start PIO, with the divisors worked out from the system clock just configured
+    let sys_clk_hz = clocks.system_clock.freq().to_Hz();
+    // a system clock that misses some presets could leave the jumpers, or a later switch, on a
+    // rate that can not be set, so only trust the jumpers when every preset is reachable
+    let target_lrck_freq = match check_all_divisors(sys_clk_hz as f32) {
+        Ok(()) => target_lrck_freq,
+        Err(_error) => {
+            #[cfg(feature = "defmt")]
+            defmt::warn!("not every rate fits the divisor: {}", defmt::Debug2Format(&_error));
+            FALLBACK_FREQUENCY
+        }
+    };
+    let mut i2s = I2sOutputBuilder::new(i2s_pins, target_lrck_freq, sys_clk_hz)
+        .bck_ratio(target_bck_ratio)
+        .fifo_join(fifo_join)
+        .mute_pin(mute_pin)
+        .build(&mut pio, sm0)
+        .unwrap();
+    let _bck_ratio = i2s.bck_ratio();
+    // the divisor is quantized to 1/256, which leaves BCK and LRCK a little off pitch
+    #[cfg(feature = "defmt")]
+    defmt::info!("{} Hz, divisor error {} ppm", i2s.lrck_freq(), i2s.divisor_error_ppm());
+    // and what every other preset would come out at on this board, should the rate be switched
+    #[cfg(feature = "defmt")]
+    for achieved in frequency_accuracy_report(sys_clk_hz as f32, target_bck_ratio).into_iter().flatten() {
+        defmt::info!(
+            "{} Hz at {}fs: LRCK {} Hz ({} ppm), BCK {} Hz ({} ppm)",
+            achieved.sample_frequency.as_hz(),
+            achieved.bck_ratio.multiplier().unwrap_or(64.0),
+            achieved.lrck_hz,
+            achieved.lrck_ppm,
+            achieved.bck_hz,
+            achieved.bck_ppm
+        );
+    }
+    timer.delay_ms(500);
+    // the clocks are stable and the state machine running, so the DAC can come out of mute
+    i2s.unmute();
+
+    if BUTTON_RETUNE_DEMO {
+        // button between gpio15 and ground
+        let mut button = pins.gpio15.into_pull_up_input();
+        button_retune_demo(&mut i2s, &mut button, led_pin.as_mut(), &timer);
+    }
+
+    if USB_SPEAKER_DEMO {
+        // full speed USB can not carry 24 bit stereo at 192khz, so the speaker starts at 48khz
+        let usb_rate = SampleFrequency::Freq48khz;
+        i2s.set_sample_frequency(usb_rate).unwrap();
+        let usb_bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
+            pac.USBCTRL_REGS,
+            pac.USBCTRL_DPRAM,
+            clocks.usb_clock,
+            true,
+            &mut pac.RESETS,
+        ));
+        let mut speaker = Uac2Speaker::new(&usb_bus, usb_rate).unwrap();
+        // pid.codes test VID/PID, only for development
+        let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("Pico_I2S")
+            .product("Pico I2S speaker")
+            .serial_number("0001")
+            .composite_with_iads()
+            .build();
+        #[cfg(feature = "defmt")]
+        let mut report_us = timer.get_counter().ticks();
+        loop {
+            usb_dev.poll(&mut [&mut speaker]);
+            // the host picks the rate, the divisor is recomputed and the state machine restarted
+            if let Some(rate) = speaker.take_rate_change() {
+                i2s.set_sample_frequency(rate).unwrap();
+            }
+            speaker.drain_into(&mut i2s);
+
+            // how the drift correction tracks the host, the fill should hover near the target
+            #[cfg(feature = "defmt")]
+            if timer.get_counter().ticks() - report_us >= 1_000_000 {
+                report_us = timer.get_counter().ticks();
+                defmt::info!(
+                    "{} frames buffered, ratio {}",
+                    speaker.buffered_frames(),
+                    speaker.resample_ratio().unwrap_or(1.0)
+                );
+            }
+        }
+    }
+
+    if WAV_CLIP_DEMO {
+        // the clip is only ever played at the rate it was recorded at, the first `play_wav`
+        // switches the output to it
+        let clip = WavClip::parse(CHIME_WAV).unwrap();
+        loop {
+            i2s.play_wav(&clip).unwrap();
+        }
+    }
+
+    if IRQ_FEED_DEMO {
+        irq_feed_demo(&mut i2s, &timer);
+    }
+
+    if CORE1_SYNTH_DEMO {
+        // button between gpio15 and ground
+        let mut button = pins.gpio15.into_pull_up_input();
+        core1_synth_demo(i2s, &mut pac.PSM, &mut pac.PPB, &mut sio.fifo, &mut button, &timer);
+    }
+
+    if CORE1_FEED_DEMO {
+        // core1 keeps the fifo fed, core0 tops the ring up with the tone and lights the LED
+        // if core1 ever finds the ring empty
+        let ring = cortex_m::singleton!(: SampleRing<CORE1_RING_WORDS> = SampleRing::<CORE1_RING_WORDS>::new()).unwrap();
+        let (mut producer, consumer) = ring.split();
+        let mut multicore = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
+        let cores = multicore.cores();
+        // SAFETY: the stack is only ever handed to core1, and only once
+        let stack = unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK.mem) };
+        i2s.spawn_core1_feed(&mut cores[1], stack, consumer).unwrap();
+
+        let mut position = 0;
+        loop {
+            position += producer.push(&SINE_FRAMES[position..]);
+            if position == SINE_FRAMES.len() {
+                position = 0;
+            }
+            if producer.take_underruns() > 0 {
+                if let Some(led) = led_pin.as_mut() {
+                    let _ = led.set_low();
+                }
+            }
+        }
+    }
+
+    if PWM_FALLBACK_DEMO {
+        // gpio0 through an RC low pass (e.g. 1k and 15nF) into an amplified speaker. 32khz keeps
+        // the carrier as far above the filter corner as PWM allows
+        let mut slices = hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS);
+        slices.pwm0.channel_a.output_to(pins.gpio0);
+        let mut pwm = PwmOutput::new(slices.pwm0, PwmChannel::A, SampleFrequency::Freq32khz, sys_clk_hz).unwrap();
+        let mut oscillator = SineOscillator::new(440.0, pwm.lrck_freq(), AMPLITUDE);
+        loop {
+            pwm.write(oscillator.next_sample());
+        }
+    }
+
+    if RECORD_PLAYBACK_DEMO {
+        // an I2S ADC in slave mode, e.g. a PCM1808, with DOUT on gpio2, BCK on gpio3 and LRCK on
+        // gpio4. The input runs its own clocks from sm2 of PIO1 at the output's rate.
+        let record_pins = I2sPins::from_pio1(
+            pins.gpio2.into_function(),
+            pins.gpio3.into_function(),
+            pins.gpio4.into_function(),
+        )
+        .unwrap();
+        let mut input = I2sInput::new(&mut pio1, sm2, record_pins, target_lrck_freq, sys_clk_hz).unwrap();
+        let dma = pac.DMA.split(&mut pac.RESETS);
+        let mut channel = dma.ch0;
+        let mut buffer: &'static mut [u32] = cortex_m::singleton!(: [u32; RECORD_WORDS] = [0; RECORD_WORDS]).unwrap();
+        loop {
+            // while a recording plays back nobody reads the input, so its fifo fills and the ADC's
+            // clocks pause until the next capture starts
+            let capture = input.start_dma_capture(channel, buffer);
+            let (returned, recorded) = input.finish_dma_capture(capture);
+            for frame in recorded.chunks_exact(2) {
+                i2s.write_stereo(
+                    cast_from_u32_as_i32(frame[0], BitDepth::Bits24),
+                    cast_from_u32_as_i32(frame[1], BitDepth::Bits24),
+                );
+            }
+            channel = returned;
+            buffer = recorded;
+        }
+    }
+
+    if LOOPBACK_DEMO {
+        // sm2 follows the output's own clocks and reads its data pin, so no wiring is needed. What
+        // comes back is what went out a few frames earlier, with the right channel inverted so a
+        // swap shows up too
+        let mut input = I2sInput::follow(&mut pio1, sm2, i2s.pins().data, &i2s).unwrap();
+        let mut oscillator = SineOscillator::new(1000.0, i2s.lrck_freq(), AMPLITUDE);
+        let mut sent = [(0, 0); LOOPBACK_HISTORY];
+        let mut position = 0;
+        loop {
+            let sample = oscillator.next_sample();
+            i2s.write_stereo(sample, -sample);
+            sent[position] = (sample, -sample);
+            position = (position + 1) % LOOPBACK_HISTORY;
+            let received = input.read_stereo();
+            if let Some(led) = led_pin.as_mut() {
+                let _ = if sent.contains(&received) { led.set_high() } else { led.set_low() };
+            }
+        }
+    }
+
+    if DMA_PLAYBACK {
+        // The DMA loop plays the same tone from the interleaved frames built at compile time, read
+        // straight out of flash. The CPU only requeues the buffer and blinks the LED.
+        let dma = pac.DMA.split(&mut pac.RESETS);
+        let mut dma_loop = i2s.start_dma((dma.ch0, dma.ch1), &SINE_FRAMES);
+        loop {
+            dma_loop.poll();
+            if let Some(led) = led_pin.as_mut() {
+                let lit = (timer.get_counter().ticks() / BLINK_HALF_PERIOD_US) & 1 == 0;
+                let _ = if lit { led.set_high() } else { led.set_low() };
+            }
+        }
+    }
+
+    // Write data to the TX FIFO
+    #[allow(clippy::empty_loop)]
+    loop {
+        i2s.write_channels(&left, &right);
+    }
+}
@@ -0,0 +1,194 @@
+//! Running the audio on core1: a lock-free ring carrying audio over from core0, and the commands
+//! core0 sends when core1 generates the audio itself.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use rp2040_hal::pio::{PIOExt, Tx, SM0};
+
+use crate::sample::CHANNELS;
+
+/// # Purpose
+/// A single producer, single consumer ring of packed fifo words shared between the two cores,
+/// see `SampleRing::split`. Only plain atomic loads and stores are used, which is all the
+/// Cortex-M0+ has, so each index and the underrun count has exactly one core writing it.
+///
+/// `N` is the ring size in words and has to be a power of two, so the free running indices stay
+/// valid across wrapping, and even, so it holds whole frames.
+pub struct SampleRing<const N: usize> {
+    words: UnsafeCell<[u32; N]>,
+    // free running positions, reduced modulo `N` on use. `write` belongs to the producer, `read`
+    // and `underruns` to the consumer.
+    write: AtomicUsize,
+    read: AtomicUsize,
+    underruns: AtomicU32,
+}
+
+// SAFETY: `split` hands out one producer and one consumer, the producer only writes slots between
+// `write` and `read` + N and the consumer only reads slots between `read` and `write`, each index
+// published with release ordering after the slots it covers
+unsafe impl<const N: usize> Sync for SampleRing<N> {}
+
+impl<const N: usize> SampleRing<N> {
+    /// # Purpose
+    /// An empty ring, const so it can be the initializer of a static or a
+    /// `cortex_m::singleton!`.
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two() && N >= CHANNELS, "the ring size has to be a power of two");
+        SampleRing {
+            words: UnsafeCell::new([0; N]),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            underruns: AtomicU32::new(0),
+        }
+    }
+
+    /// # Purpose
+    /// Splits the ring into its two ends, the producer for core0 and the consumer for core1.
+    /// Borrowing the ring mutably for as long as the ends live makes sure there is only one of
+    /// each.
+    pub fn split(&mut self) -> (RingProducer<'_, N>, RingConsumer<'_, N>) {
+        (RingProducer { ring: self, underruns_seen: 0 }, RingConsumer { ring: self, starved: true })
+    }
+
+    /// # Purpose
+    /// Words waiting between the two indices.
+    fn len(&self) -> usize {
+        self.write.load(Ordering::Acquire).wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+impl<const N: usize> Default for SampleRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Purpose
+/// The core0 end of a `SampleRing`, queueing whole frames of packed fifo words.
+pub struct RingProducer<'a, const N: usize> {
+    ring: &'a SampleRing<N>,
+    // the consumer's underrun count at the last `take_underruns`
+    underruns_seen: u32,
+}
+
+impl<const N: usize> RingProducer<'_, N> {
+    /// # Purpose
+    /// Returns the number of whole frames `push` can take right now.
+    pub fn free_frames(&self) -> usize {
+        (N - self.ring.len()) / CHANNELS
+    }
+
+    /// # Purpose
+    /// Queues as many whole frames of packed fifo words from `words` as fit and returns the
+    /// number of words taken. The frames only become visible to the consumer once all of them are
+    /// in, so it never sees half a frame.
+    pub fn push(&mut self, words: &[u32]) -> usize {
+        let count = (words.len() / CHANNELS).min(self.free_frames()) * CHANNELS;
+        let write = self.ring.write.load(Ordering::Relaxed);
+        let slots = self.ring.words.get() as *mut u32;
+        for (i, word) in words[..count].iter().enumerate() {
+            // SAFETY: the slots from `write` up to `read` + N are the producer's until published
+            unsafe { slots.add(write.wrapping_add(i) % N).write_volatile(*word) };
+        }
+        self.ring.write.store(write.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// # Purpose
+    /// Returns the number of times core1 found the ring empty with room in the fifo since the
+    /// last call, i.e. how often the producer fell behind. A rising count means core0 has to
+    /// produce faster or keep the ring fuller.
+    pub fn take_underruns(&mut self) -> u32 {
+        let total = self.ring.underruns.load(Ordering::Relaxed);
+        let new = total.wrapping_sub(self.underruns_seen);
+        self.underruns_seen = total;
+        new
+    }
+}
+
+/// # Purpose
+/// The core1 end of a `SampleRing`, see `I2sOutput::spawn_core1_feed`.
+pub struct RingConsumer<'a, const N: usize> {
+    ring: &'a SampleRing<N>,
+    // the ring was found empty and the underrun already counted
+    starved: bool,
+}
+
+impl<const N: usize> RingConsumer<'_, N> {
+    /// # Purpose
+    /// Moves words from the ring into `tx` until the fifo is full or the ring is empty, counting
+    /// an underrun the first time the ring is found empty after it had words.
+    pub fn drain_into<P: PIOExt>(&mut self, tx: &mut Tx<(P, SM0)>) {
+        let write = self.ring.write.load(Ordering::Acquire);
+        let mut read = self.ring.read.load(Ordering::Relaxed);
+        let slots = self.ring.words.get() as *const u32;
+        while read != write && !tx.is_full() {
+            // SAFETY: the slots from `read` up to `write` were published by the producer
+            tx.write(unsafe { slots.add(read % N).read_volatile() });
+            read = read.wrapping_add(1);
+            self.starved = false;
+        }
+        self.ring.read.store(read, Ordering::Release);
+
+        if read == write && !tx.is_full() && !self.starved {
+            self.starved = true;
+            let underruns = self.ring.underruns.load(Ordering::Relaxed);
+            self.ring.underruns.store(underruns.wrapping_add(1), Ordering::Relaxed);
+        }
+    }
+}
+
+/// # Purpose
+/// The loop run on core1 by `I2sOutput::spawn_core1_feed`, keeping `tx` fed from `consumer` for
+/// as long as the chip runs.
+pub(crate) fn feed_loop<P: PIOExt, const N: usize>(mut tx: Tx<(P, SM0)>, mut consumer: RingConsumer<'static, N>) -> ! {
+    loop {
+        consumer.drain_into(&mut tx);
+    }
+}
+
+// opcodes in the top byte of a command word, the payload is in the low 24 bits
+const OP_SET_TONE: u32 = 1;
+const OP_MUTE: u32 = 2;
+const OP_UNMUTE: u32 = 3;
+const PAYLOAD_MASK: u32 = 0xFF_FFFF;
+
+/// # Purpose
+/// A control command core0 sends to core1 while core1 generates the audio, one word each so it
+/// fits the SIO inter-core fifo, see `to_word` and `from_word`.
+/// # Members
+/// - SetTone:  retune the tone to `hz`, whole Hertz up to 0xFF_FFFF
+/// - Mute:     ramp the output down, see `I2sOutput::mute`
+/// - Unmute:   ramp it back up, see `I2sOutput::unmute`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    SetTone { hz: u32 },
+    Mute,
+    Unmute,
+}
+
+impl Command {
+    /// # Purpose
+    /// Packs the command into one fifo word, the opcode in the top byte and the payload below
+    /// it. A tone above 0xFF_FFFF Hz is clamped.
+    pub fn to_word(self) -> u32 {
+        match self {
+            Command::SetTone { hz } => OP_SET_TONE << 24 | hz.min(PAYLOAD_MASK),
+            Command::Mute => OP_MUTE << 24,
+            Command::Unmute => OP_UNMUTE << 24,
+        }
+    }
+
+    /// # Purpose
+    /// Unpacks a word written by `to_word`, or `None` for a word that is not a command, such as
+    /// one left in the fifo by the boot handshake.
+    pub fn from_word(word: u32) -> Option<Self> {
+        match word >> 24 {
+            OP_SET_TONE => Some(Command::SetTone { hz: word & PAYLOAD_MASK }),
+            OP_MUTE => Some(Command::Mute),
+            OP_UNMUTE => Some(Command::Unmute),
+            _ => None,
+        }
+    }
+}
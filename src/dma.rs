@@ -0,0 +1,278 @@
+//! DMA transfers that feed the I2S tx fifo, or drain the input's rx fifo, without the CPU moving
+//! every word.
+
+use rp2040_hal::dma::double_buffer::{Config, ReadNext, Transfer};
+use rp2040_hal::dma::{single_buffer, Pace, SingleChannel};
+use rp2040_hal::pio::{PIOExt, Rx, Tx, SM0, SM2};
+
+use crate::sample::CHANNELS;
+
+// The transfer of a running loop: one channel playing the buffer, the other queued with it
+type LoopTransfer<CH1, CH2, P> = Transfer<CH1, CH2, &'static [u32], Tx<(P, SM0)>, ReadNext<&'static [u32]>>;
+
+/// # Purpose
+/// Plays one buffer of packed fifo words over and over through DMA, paced by the tx fifo's DREQ.
+/// Started with `I2sOutput::start_dma` and handed back with `I2sOutput::stop_dma`.
+///
+/// The two channels are chained, so while one plays the buffer the other is already queued to
+/// play it again and the fifo never sees a gap between passes. The CPU only has to requeue the
+/// finished channel, which `poll` does in a few instructions. `poll` has to be called at least
+/// once per pass of the buffer (10ms for a 1920 frame buffer at 192khz). If a pass is missed the
+/// fifo runs dry and the state machine clocks out silence until the next `poll`. It only ever
+/// takes whole frames from the fifo, so left and right stay in their slots across the gap.
+pub struct DmaLoop<CH1: SingleChannel, CH2: SingleChannel, P: PIOExt> {
+    // `None` only while `poll` or `stop` swaps the transfer
+    transfer: Option<LoopTransfer<CH1, CH2, P>>,
+    buffer: &'static [u32],
+    passes: u32,
+}
+
+impl<CH1: SingleChannel, CH2: SingleChannel, P: PIOExt> DmaLoop<CH1, CH2, P> {
+    /// # Purpose
+    /// Starts playing `buffer` into `tx` on `channels` and queues the second pass behind it.
+    pub(crate) fn start(channels: (CH1, CH2), buffer: &'static [u32], tx: Tx<(P, SM0)>) -> Self {
+        let mut config = Config::new(channels, buffer, tx);
+        config.pace(Pace::PreferSink);
+        let transfer = config.start().read_next(buffer);
+        DmaLoop {
+            transfer: Some(transfer),
+            buffer,
+            passes: 0,
+        }
+    }
+
+    /// # Purpose
+    /// Requeues the buffer if a pass has finished since the last call. Returns true if it did.
+    pub fn poll(&mut self) -> bool {
+        match self.transfer.take() {
+            Some(transfer) if transfer.is_done() => {
+                let (_, playing) = transfer.wait();
+                self.transfer = Some(playing.read_next(self.buffer));
+                self.passes = self.passes.wrapping_add(1);
+                true
+            }
+            other => {
+                self.transfer = other;
+                false
+            }
+        }
+    }
+
+    /// # Purpose
+    /// The number of passes of the buffer finished so far, wrapping at `u32::MAX`.
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    /// # Purpose
+    /// Lets the pass in progress and the one queued behind it finish, then gives back the channels
+    /// and the tx fifo. Ending on a pass boundary keeps the next word written in the left slot.
+    pub(crate) fn stop(mut self) -> (CH1, CH2, Tx<(P, SM0)>) {
+        let transfer = self.transfer.take().expect("a DMA loop always holds its transfer");
+        let (_, last) = transfer.wait();
+        let ((ch1, ch2), _, tx) = last.wait();
+        (ch1, ch2, tx)
+    }
+}
+
+// A buffer of packed fifo words streamed by a `DmaStream`
+type StreamBuffer = &'static mut [u32];
+
+// Where the two channels of a `DmaStream` are at
+enum StreamState<CH1: SingleChannel, CH2: SingleChannel, P: PIOExt> {
+    // nothing playing, before the first buffer and after an underrun
+    Idle((CH1, CH2), Tx<(P, SM0)>),
+    // one buffer playing with nothing queued behind it
+    Playing(Transfer<CH1, CH2, StreamBuffer, Tx<(P, SM0)>, ()>),
+    // one buffer playing and the next queued behind it
+    Queued(Transfer<CH1, CH2, StreamBuffer, Tx<(P, SM0)>, ReadNext<StreamBuffer>>),
+}
+
+/// # Purpose
+/// Streams audio generated on the fly through DMA with two buffers in ping-pong: while one is
+/// transmitted the application fills the other. Started with `I2sOutput::start_dma_stream` and
+/// handed back with `I2sOutput::stop_dma_stream`.
+///
+/// # Use
+/// `next_buffer` hands out a buffer that is free to fill and `commit` queues it behind the one
+/// playing, then the same again for the other buffer once `next_buffer` returns it:
+///
+/// - `next_buffer` returns `None` while both buffers are with the DMA, i.e. one playing and one
+///   queued. It calls `poll` first, so it doubles as the check for a finished buffer.
+/// - If the playing buffer runs out with nothing queued, that is an underrun: the fifo runs dry,
+///   the state machine clocks out silence and `take_underruns` counts it. The next `commit`
+///   restarts the stream, so the application decides whether to fill that buffer with silence
+///   (mute) or leave the old contents in it (repeat). The state machine only ever takes whole
+///   frames from the fifo, so the restart picks up in the left slot.
+///
+/// With two buffers of N frames the application has the play time of one buffer, N / fs, to fill
+/// the next, e.g. 1.3ms for 256 frames at 192khz.
+pub struct DmaStream<CH1: SingleChannel, CH2: SingleChannel, P: PIOExt> {
+    // `None` only while a method swaps the state
+    state: Option<StreamState<CH1, CH2, P>>,
+    // buffers with the application, both of them before the first commit and after an underrun.
+    // Kept in the order they came back with the first slot filled first, so the buffer handed out
+    // by `next_buffer` stays at the front until it is committed.
+    free: [Option<StreamBuffer>; 2],
+    underruns: u32,
+}
+
+impl<CH1: SingleChannel, CH2: SingleChannel, P: PIOExt> DmaStream<CH1, CH2, P> {
+    /// # Purpose
+    /// An idle stream over `buffers` on `channels`, playing into `tx` once the first buffer is
+    /// committed. A trailing odd word of a buffer is half a frame and is never used.
+    pub(crate) fn new(channels: (CH1, CH2), buffers: [StreamBuffer; 2], tx: Tx<(P, SM0)>) -> Self {
+        let [first, second] = buffers;
+        DmaStream {
+            state: Some(StreamState::Idle(channels, tx)),
+            free: [Some(whole_frames(first)), Some(whole_frames(second))],
+            underruns: 0,
+        }
+    }
+
+    /// # Purpose
+    /// Takes back a buffer the DMA has finished with and notes an underrun if nothing was queued
+    /// behind it. Cheap enough to call from a busy loop.
+    pub fn poll(&mut self) {
+        self.state = match self.state.take() {
+            Some(StreamState::Queued(transfer)) if transfer.is_done() => {
+                let (played, playing) = transfer.wait();
+                self.give_back(played);
+                Some(StreamState::Playing(playing))
+            }
+            Some(StreamState::Playing(transfer)) if transfer.is_done() => {
+                let (channels, played, tx) = transfer.wait();
+                self.give_back(played);
+                self.underruns = self.underruns.saturating_add(1);
+                Some(StreamState::Idle(channels, tx))
+            }
+            other => other,
+        };
+    }
+
+    /// # Purpose
+    /// The buffer to fill next, or `None` while the DMA holds both. The same buffer is returned
+    /// until it is committed.
+    pub fn next_buffer(&mut self) -> Option<&mut [u32]> {
+        self.poll();
+        self.free[0].as_deref_mut()
+    }
+
+    /// # Purpose
+    /// Queues the buffer returned by `next_buffer` behind the one playing, or starts playing it
+    /// straight away if the stream is idle. Returns false if there was no free buffer to commit.
+    pub fn commit(&mut self) -> bool {
+        self.poll();
+        let buffer = match self.free[0].take() {
+            Some(buffer) => buffer,
+            None => return false,
+        };
+        self.free.swap(0, 1);
+
+        match self.state.take() {
+            Some(StreamState::Idle(channels, tx)) => {
+                let mut config = Config::new(channels, buffer, tx);
+                config.pace(Pace::PreferSink);
+                self.state = Some(StreamState::Playing(config.start()));
+                true
+            }
+            Some(StreamState::Playing(transfer)) => {
+                self.state = Some(StreamState::Queued(transfer.read_next(buffer)));
+                true
+            }
+            other => {
+                // a free buffer means at most one is with the DMA, so this can not happen
+                self.free[1] = self.free[0].take();
+                self.free[0] = Some(buffer);
+                self.state = other;
+                false
+            }
+        }
+    }
+
+    /// # Purpose
+    /// Returns the number of underruns since the last call, and clears it.
+    pub fn take_underruns(&mut self) -> u32 {
+        let underruns = self.underruns;
+        self.underruns = 0;
+        underruns
+    }
+
+    /// # Purpose
+    /// Lets the buffers already committed play out, then gives back the channels, both buffers
+    /// and the tx fifo.
+    pub(crate) fn stop(mut self) -> (CH1, CH2, [StreamBuffer; 2], Tx<(P, SM0)>) {
+        let ((ch1, ch2), tx) = match self.state.take().expect("a DMA stream always holds its state") {
+            StreamState::Idle(channels, tx) => (channels, tx),
+            StreamState::Playing(transfer) => {
+                let (channels, played, tx) = transfer.wait();
+                self.give_back(played);
+                (channels, tx)
+            }
+            StreamState::Queued(transfer) => {
+                let (played, playing) = transfer.wait();
+                self.give_back(played);
+                let (channels, played, tx) = playing.wait();
+                self.give_back(played);
+                (channels, tx)
+            }
+        };
+        let [first, second] = self.free;
+        let buffers = [
+            first.expect("both buffers are back once the stream is stopped"),
+            second.expect("both buffers are back once the stream is stopped"),
+        ];
+        (ch1, ch2, buffers, tx)
+    }
+
+    /// # Purpose
+    /// Returns a buffer the DMA has finished with behind any buffer already free.
+    fn give_back(&mut self, buffer: StreamBuffer) {
+        if self.free[0].is_none() {
+            self.free[0] = Some(buffer);
+        } else {
+            self.free[1] = Some(buffer);
+        }
+    }
+}
+
+/// # Purpose
+/// Records one buffer of words from the input's rx fifo through DMA, paced by the fifo's DREQ.
+/// Started with `I2sInput::start_dma_capture` and handed back with
+/// `I2sInput::finish_dma_capture`.
+///
+/// The channel moves every word as it arrives, so the fifo never fills and the ADC's clocks
+/// never stall however long the buffer is. The buffer is only the application's again once the
+/// capture is finished, `is_done` tells without blocking whether that will wait.
+pub struct DmaCapture<CH: SingleChannel, P: PIOExt> {
+    transfer: single_buffer::Transfer<CH, Rx<(P, SM2)>, StreamBuffer>,
+}
+
+impl<CH: SingleChannel, P: PIOExt> DmaCapture<CH, P> {
+    /// # Purpose
+    /// Starts filling `buffer` from `rx` on `channel`, trimmed to whole frames.
+    pub(crate) fn start(channel: CH, buffer: StreamBuffer, rx: Rx<(P, SM2)>) -> Self {
+        let mut config = single_buffer::Config::new(channel, rx, whole_frames(buffer));
+        config.pace(Pace::PreferSource);
+        DmaCapture { transfer: config.start() }
+    }
+
+    /// # Purpose
+    /// Returns true once the buffer is full.
+    pub fn is_done(&self) -> bool {
+        self.transfer.is_done()
+    }
+
+    /// # Purpose
+    /// Waits for the buffer to fill, then gives back the channel, the rx fifo and the buffer.
+    pub(crate) fn wait(self) -> (CH, Rx<(P, SM2)>, StreamBuffer) {
+        self.transfer.wait()
+    }
+}
+
+/// # Purpose
+/// Trims a buffer to whole frames, dropping a trailing odd word.
+fn whole_frames(buffer: StreamBuffer) -> StreamBuffer {
+    let len = buffer.len() - buffer.len() % CHANNELS;
+    buffer.split_at_mut(len).0
+}
@@ -0,0 +1,469 @@
+//! Gain, dither, envelopes, limiting and drift resampling applied to samples on their way to the DAC.
+
+use crate::sample::FULL_SCALE_24BIT;
+
+// How fast the true-peak limiter gain recovers towards unity, as a right shift per sample
+pub const LIMITER_RELEASE_SHIFT: u8 = 10;
+// A Q15 gain of 1.0
+pub const UNITY_GAIN_Q15: u16 = 0x8000;
+// Seed of the dither generator, any value but 0 works for a xorshift
+pub const DITHER_SEED: u32 = 0x2545_F491;
+// Furthest the drift resampler may move its ratio away from 1.0, in parts per million. Crystals
+// are good to tens of ppm, so this leaves room for the divisor quantization and a bad host.
+pub const MAX_DRIFT_PPM: f32 = 1000.0;
+// Share of the distance to the latest fill level the drift resampler's average moves per update
+pub const FILL_SMOOTHING: f32 = 1.0 / 64.0;
+// Share of the distance to the ratio the fill asks for the drift resampler moves per update
+pub const RATIO_SLEW: f32 = 1.0 / 256.0;
+// Range of levels `db_to_gain` works out, in dB. Below it the gain is 0, far under the 24 bit
+// noise floor at -144dB, and above it the gain is held, 2^33 is already far past any useful boost
+pub const MIN_GAIN_DB: f32 = -200.0;
+pub const MAX_GAIN_DB: f32 = 200.0;
+
+/// # Purpose
+/// Scales a 24 bit sample by the Q15 fixed point `gain`, where `UNITY_GAIN_Q15` (0x8000) is unity
+/// and anything above it boosts, rounding to nearest. The result saturates at the 24 bit limits
+/// instead of wrapping. One 32x32->64 bit multiply, cheap enough for every sample at 192khz.
+/// Unity gain returns the sample unchanged, 0x7FFF comes out at most one LSB low.
+pub fn apply_q15_gain(sample: i32, gain: u16) -> i32 {
+    let scaled = (sample as i64 * gain as i64 + (1 << 14)) >> 15;
+    scaled.clamp(-(FULL_SCALE_24BIT as i64) - 1, FULL_SCALE_24BIT as i64) as i32
+}
+
+/// # Purpose
+/// Like `apply_q15_gain`, but with TPDF dither added to the bits below the 24 bit LSB before they
+/// are rounded away, so a quiet tone is left with a little noise instead of distortion that
+/// follows the signal. The dither spans at most +-1 LSB, triangular, so the result is within
+/// 1.5 LSB of the exact product. Unity gain still returns the sample unchanged, as nothing is
+/// rounded away there.
+pub fn apply_q15_gain_dithered(sample: i32, gain: u16, dither: &mut Dither) -> i32 {
+    if gain == UNITY_GAIN_Q15 {
+        return sample;
+    }
+    let scaled = (sample as i64 * gain as i64 + dither.tpdf_q15() as i64 + (1 << 14)) >> 15;
+    scaled.clamp(-(FULL_SCALE_24BIT as i64) - 1, FULL_SCALE_24BIT as i64) as i32
+}
+
+/// # Purpose
+/// A xorshift32 generator of dither noise, small and fast enough to run twice per sample on the
+/// Cortex-M0+ and needing nothing from `std`. Its noise wants to be uncorrelated with the audio,
+/// not unpredictable, so a fixed seed is fine.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    /// # Purpose
+    /// A generator starting from `seed`, where 0, which a xorshift never leaves, is replaced by
+    /// `DITHER_SEED`.
+    pub const fn new(seed: u32) -> Self {
+        Dither { state: if seed == 0 { DITHER_SEED } else { seed } }
+    }
+
+    /// # Purpose
+    /// The next 32 bits of the sequence.
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// # Purpose
+    /// Triangular noise of +-1 LSB in Q15, i.e. strictly between -32768 and 32768: the sum of two
+    /// uniform values of half an LSB either side of zero.
+    pub fn tpdf_q15(&mut self) -> i32 {
+        let a = (self.next_u32() >> 17) as i32;
+        let b = (self.next_u32() >> 17) as i32;
+        a + b - (1 << 15) + 1
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new(DITHER_SEED)
+    }
+}
+
+/// # Purpose
+/// Converts a level in dBFS to a linear gain, i.e. 10^(db/20), without pulling in `libm`.
+/// 10^(db/20) is rewritten as 2^x with x = db * log2(10) / 20. x is rounded to the nearest whole
+/// number, which is applied as repeated doubling/halving, and 2^frac for the remaining
+/// frac in [-0.5, 0.5) uses a 5th order series good to a few parts per million.
+///
+/// Levels under `MIN_GAIN_DB`, -inf and NaN give a gain of 0 and levels over `MAX_GAIN_DB` are
+/// held there, so the doubling/halving loops run at most about 33 times whatever `db` is.
+pub fn db_to_gain(db: f32) -> f32 {
+    if db.is_nan() || db < MIN_GAIN_DB {
+        return 0.0;
+    }
+    let x = db.min(MAX_GAIN_DB) * 0.166_096_4;
+    let mut whole = (x + 0.5) as i32;
+    if (whole as f32) > x + 0.5 {
+        whole -= 1;
+    }
+    let f = x - whole as f32;
+    let mut gain = 1.0
+        + f * (core::f32::consts::LN_2
+            + f * (0.240_226_5 + f * (0.055_504_1 + f * (0.009_618_1 + f * 0.001_333_3))));
+    while whole > 0 {
+        gain *= 2.0;
+        whole -= 1;
+    }
+    while whole < 0 {
+        gain *= 0.5;
+        whole += 1;
+    }
+    gain
+}
+
+/// # Purpose
+/// Where an `Envelope` is.
+/// # Members
+/// - Attack:   ramping up from silence after `trigger`
+/// - Sustain:  at unity, the state an envelope starts in
+/// - Release:  ramping down after `release`
+/// - Done:     silent since a release ended, until the next `trigger`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvelopeStage {
+    Attack,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// # Purpose
+/// A linear attack and release envelope as a Q15 gain per frame, for fading a stream in at its
+/// start and out at its end instead of starting or cutting it at full amplitude, which clicks.
+///
+/// The gain is worked out from the frame count into the ramp rather than stepped, so an attack of
+/// N frames gives exactly 0 on its first frame and unity from frame N on, and a release of N
+/// frames reaches 0 on frame N whatever gain it started from. A ramp of 0 frames jumps.
+pub struct Envelope {
+    attack_frames: u32,
+    release_frames: u32,
+    stage: EnvelopeStage,
+    // frames into the current ramp, and the gain a release started from
+    position: u32,
+    release_from: u16,
+}
+
+impl Envelope {
+    /// # Purpose
+    /// An envelope with the given ramp lengths, sustaining at unity until it is triggered.
+    pub const fn new(attack_frames: u32, release_frames: u32) -> Self {
+        Envelope {
+            attack_frames,
+            release_frames,
+            stage: EnvelopeStage::Sustain,
+            position: 0,
+            release_from: UNITY_GAIN_Q15,
+        }
+    }
+
+    /// # Purpose
+    /// Sets the length of the fade in, in frames. An attack under way carries on over the new
+    /// length, and ends straight away if it is already that far in.
+    pub fn set_attack_frames(&mut self, frames: u32) {
+        self.attack_frames = frames;
+        self.settle();
+    }
+
+    /// # Purpose
+    /// Sets the length of the fade out, in frames. A release under way carries on over the new
+    /// length, and ends straight away if it is already that far in.
+    pub fn set_release_frames(&mut self, frames: u32) {
+        self.release_frames = frames;
+        self.settle();
+    }
+
+    /// # Purpose
+    /// Starts the attack from silence.
+    pub fn trigger(&mut self) {
+        self.stage = if self.attack_frames == 0 { EnvelopeStage::Sustain } else { EnvelopeStage::Attack };
+        self.position = 0;
+    }
+
+    /// # Purpose
+    /// Starts the release from the current gain, so releasing during the attack does not jump.
+    pub fn release(&mut self) {
+        self.release_from = self.gain();
+        self.stage = if self.release_frames == 0 { EnvelopeStage::Done } else { EnvelopeStage::Release };
+        self.position = 0;
+    }
+
+    /// # Purpose
+    /// Returns where the envelope is.
+    pub fn stage(&self) -> EnvelopeStage {
+        self.stage
+    }
+
+    /// # Purpose
+    /// Returns the Q15 gain for the next frame and moves one frame on.
+    pub fn next_gain(&mut self) -> u16 {
+        let gain = self.gain();
+        match self.stage {
+            EnvelopeStage::Attack | EnvelopeStage::Release => self.position += 1,
+            EnvelopeStage::Sustain | EnvelopeStage::Done => {}
+        }
+        self.settle();
+        gain
+    }
+
+    /// # Purpose
+    /// Moves on from a ramp that has run its length.
+    fn settle(&mut self) {
+        if self.stage == EnvelopeStage::Attack && self.position >= self.attack_frames {
+            self.stage = EnvelopeStage::Sustain;
+        }
+        if self.stage == EnvelopeStage::Release && self.position >= self.release_frames {
+            self.stage = EnvelopeStage::Done;
+        }
+    }
+
+    /// # Purpose
+    /// The Q15 gain at the current position. `settle` keeps the position inside the ramp, the
+    /// guards here only stop a ramp of 0 frames dividing by zero. The products are taken in u64,
+    /// a Q15 gain times a ramp of over 131072 frames (under 3s at 48khz) does not fit a u32.
+    fn gain(&self) -> u16 {
+        match self.stage {
+            EnvelopeStage::Attack if self.attack_frames == 0 => UNITY_GAIN_Q15,
+            EnvelopeStage::Attack => {
+                let done = self.position.min(self.attack_frames);
+                (UNITY_GAIN_Q15 as u64 * done as u64 / self.attack_frames as u64) as u16
+            }
+            EnvelopeStage::Sustain => UNITY_GAIN_Q15,
+            EnvelopeStage::Release if self.release_frames == 0 => 0,
+            EnvelopeStage::Release => {
+                let left = self.release_frames.saturating_sub(self.position);
+                (self.release_from as u64 * left as u64 / self.release_frames as u64) as u16
+            }
+            EnvelopeStage::Done => 0,
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+/// # Purpose
+/// A per channel limiter that keeps the estimated true (inter-sample) peak under a ceiling, so
+/// that the DAC's reconstruction or any later upsampling does not produce overs between samples
+/// that never show up in the sample values themselves.
+///
+/// # True-peak estimation
+/// The signal is oversampled 4x by Catmull-Rom interpolation over the last four samples, giving
+/// three interpolated points between each pair of samples. The interpolation weights are fixed for
+/// t = 1/4, 1/2, 3/4 and applied in integer arithmetic, so the cost is 12 multiply-accumulates and
+/// a compare per sample per channel, plus one divide on the samples where the limiter has to act.
+/// The interpolation needs one sample of lookahead, so a limited channel is delayed by one sample.
+///
+/// # Gain
+/// When the estimated peak would go over the ceiling the gain drops straight away to bring it down
+/// to the ceiling, and then recovers towards unity by 1/2^`LIMITER_RELEASE_SHIFT` of the distance
+/// each sample. The output is also clamped to the ceiling as a last line of defence.
+pub struct TruePeakLimiter {
+    // `None` disables the limiter, samples then pass straight through with no delay
+    ceiling: Option<i32>,
+    // the last four input samples, oldest first
+    history: [i32; 4],
+    // Q16 gain, 1 << 16 is unity
+    gain: i32,
+    // set whenever the limiter had to reduce the gain, cleared by `take_over`
+    over: bool,
+}
+
+impl TruePeakLimiter {
+    /// Catmull-Rom weights out of 128 for t = 1/4, 1/2 and 3/4 between history[1] and history[2]
+    const WEIGHTS: [[i64; 4]; 3] = [[-9, 111, 29, -3], [-8, 72, 72, -8], [-3, 29, 111, -9]];
+
+    /// # Purpose
+    /// A disabled limiter.
+    pub const fn new() -> Self {
+        TruePeakLimiter {
+            ceiling: None,
+            history: [0; 4],
+            gain: 1 << 16,
+            over: false,
+        }
+    }
+
+    /// # Purpose
+    /// Sets the true-peak ceiling in dBFS (0.0 is full scale), or disables the limiter with `None`.
+    pub fn set_ceiling(&mut self, dbfs: Option<f32>) {
+        self.ceiling = dbfs.map(|db| {
+            let ceiling = FULL_SCALE_24BIT as f32 * db_to_gain(db);
+            if ceiling >= FULL_SCALE_24BIT as f32 { FULL_SCALE_24BIT } else { ceiling as i32 }
+        });
+        self.history = [0; 4];
+        self.gain = 1 << 16;
+    }
+
+    /// # Purpose
+    /// Returns whether the content has hit the ceiling since the last call, and clears the flag.
+    pub fn take_over(&mut self) -> bool {
+        let over = self.over;
+        self.over = false;
+        over
+    }
+
+    /// # Purpose
+    /// Feeds one sample in and returns the limited sample one step behind it.
+    pub fn process(&mut self, sample: i32) -> i32 {
+        let ceiling = match self.ceiling {
+            Some(ceiling) => ceiling,
+            None => return sample,
+        };
+
+        self.history = [self.history[1], self.history[2], self.history[3], sample];
+        let h = self.history.map(|x| x as i64);
+
+        let mut peak = h[2].abs();
+        for weights in TruePeakLimiter::WEIGHTS.iter() {
+            let point = (weights[0] * h[0] + weights[1] * h[1] + weights[2] * h[2] + weights[3] * h[3]) / 128;
+            peak = peak.max(point.abs());
+        }
+
+        // release towards unity, then pull the gain down if this peak would still be over
+        self.gain += ((1 << 16) - self.gain) >> LIMITER_RELEASE_SHIFT;
+        if peak * self.gain as i64 > (ceiling as i64) << 16 {
+            self.gain = (((ceiling as i64) << 16) / peak) as i32;
+            self.over = true;
+        }
+
+        let out = (h[2] * self.gain as i64) >> 16;
+        out.clamp(-(ceiling as i64), ceiling as i64) as i32
+    }
+}
+
+impl Default for TruePeakLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Purpose
+/// An asynchronous sample rate converter for a stream whose source clock is not the DAC's, such
+/// as USB audio played out of the I2S clock. Stereo frames are pulled from a buffer at a ratio a
+/// little above or below 1.0 and linearly interpolated, and the ratio is steered so the buffer
+/// fill stays near a target instead of slowly draining or overflowing.
+///
+/// # Control loop
+/// `update` is called with the buffer fill once per incoming packet. The fill is averaged over
+/// about `1 / FILL_SMOOTHING` updates, since it saws by a whole packet every time, and its error
+/// against the target, as a share of the target, asks for a proportional ratio of up to
+/// `MAX_DRIFT_PPM` off 1.0: a fuller buffer is read faster. The ratio itself only slews towards
+/// that by `RATIO_SLEW` of the distance per update, so a jump in the fill is spread over a few
+/// hundred milliseconds and never heard as a change of pitch. Being proportional only, the fill
+/// settles a little off the target, by the share of `MAX_DRIFT_PPM` the real drift needs.
+///
+/// # Interpolation
+/// Fixed point throughout the sample path: the position between the two frames either side of the
+/// output is a 32 bit fraction and each output sample costs one 64 bit multiply, so it keeps up at
+/// 96khz on the Cortex-M0+. Linear interpolation is a gentle low pass on the top octave, fine for
+/// correcting a drift of a few ppm.
+pub struct DriftResampler {
+    // the input frames either side of the next output, and how far between them it is, in 2^-32
+    prev: [i32; 2],
+    next: [i32; 2],
+    phase: u32,
+    primed: bool,
+    // the ratio is 1.0 + drift, kept apart so its few ppm do not drown in an f32 around 1.0
+    drift: f32,
+    // `drift` in 2^-32 steps of the phase, as the sample path uses it
+    step: i32,
+    fill_average: f32,
+    target_fill: f32,
+}
+
+impl DriftResampler {
+    /// # Purpose
+    /// A resampler at a ratio of exactly 1.0 steering the buffer towards `target_fill` frames,
+    /// typically half the buffer.
+    pub fn new(target_fill: usize) -> Self {
+        DriftResampler {
+            prev: [0; 2],
+            next: [0; 2],
+            phase: 0,
+            primed: false,
+            drift: 0.0,
+            step: 0,
+            fill_average: target_fill as f32,
+            target_fill: target_fill as f32,
+        }
+    }
+
+    /// # Purpose
+    /// Forgets the frames and the position held, e.g. after the buffer was flushed or ran dry,
+    /// and restarts the fill average at the target. The ratio is kept, as the clocks have not
+    /// changed.
+    pub fn reset(&mut self) {
+        self.prev = [0; 2];
+        self.next = [0; 2];
+        self.phase = 0;
+        self.primed = false;
+        self.fill_average = self.target_fill;
+    }
+
+    /// # Purpose
+    /// Steers the ratio from the buffer fill in frames, see the control loop notes above. Call it
+    /// once per incoming packet, so the smoothing spans a fixed time.
+    pub fn update(&mut self, fill: usize) {
+        self.fill_average += (fill as f32 - self.fill_average) * FILL_SMOOTHING;
+        let error = ((self.fill_average - self.target_fill) / self.target_fill).clamp(-1.0, 1.0);
+        let wanted = error * MAX_DRIFT_PPM * 1E-06;
+        self.drift += (wanted - self.drift) * RATIO_SLEW;
+        self.step = (self.drift * 4_294_967_296.0) as i32;
+    }
+
+    /// # Purpose
+    /// Returns the input frames consumed per output frame, above 1.0 while the buffer is being
+    /// drawn down.
+    pub fn ratio(&self) -> f32 {
+        1.0 + self.drift
+    }
+
+    /// # Purpose
+    /// Returns the fill the control loop currently sees, averaged, in frames.
+    pub fn average_fill(&self) -> f32 {
+        self.fill_average
+    }
+
+    /// # Purpose
+    /// Produces the next output frame, pulling input frames from `pop` as the position passes
+    /// them: none, one or, when it is being read fast, two, plus two to start with. Returns `None`
+    /// without moving if `pop` runs out before the frame could be made, and starts afresh on the
+    /// next call.
+    pub fn next_frame(&mut self, mut pop: impl FnMut() -> Option<[i32; 2]>) -> Option<[i32; 2]> {
+        if !self.primed {
+            self.prev = pop()?;
+            self.next = pop()?;
+            self.primed = true;
+        }
+
+        let phase = self.phase as i64;
+        let out = [0, 1].map(|c| {
+            let prev = self.prev[c] as i64;
+            (prev + (((self.next[c] as i64 - prev) * phase) >> 32)) as i32
+        });
+
+        let position = self.phase as i64 + (1_i64 << 32) + self.step as i64;
+        self.phase = position as u32;
+        for _ in 0..(position >> 32) {
+            self.prev = self.next;
+            match pop() {
+                Some(frame) => self.next = frame,
+                None => {
+                    self.primed = false;
+                    break;
+                }
+            }
+        }
+        Some(out)
+    }
+}
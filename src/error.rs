@@ -0,0 +1,47 @@
+//! Errors reported while setting up the I2S output and the USB audio device, and while reading WAV
+//! clips.
+
+/// # Purpose
+/// Why an `I2sOutput`, an `I2sInput` or a `Uac2Speaker` could not be set up, or a sample rate not
+/// be used.
+/// # Members
+/// - DivisorOutOfRange:   the clock divisor for the requested rate is outside 1.0..65536.0
+/// - PinsNotConsecutive:  LRCK is not the pin right after BCK, which the side set needs
+/// - PinsOverlap:         the data line is on `pin`, which is already BCK or LRCK
+/// - NoProgramSpace:      the PIO's instruction memory has no room left for the I2S program
+/// - PacketTooLarge:      a USB audio packet at the requested rate is over the 1023 byte limit
+/// - UnsupportedRate:     no `SampleFrequency` preset runs at the requested rate in Hertz
+/// - UnsupportedFraming:  an `I2sInput` only follows an output in `DataFormat::I2s` at 64fs BCK
+/// - RateOutOfTolerance:  the achieved LRCK is `ppm` away from the nominal rate, more than the DAC takes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2sError {
+    DivisorOutOfRange { divisor: f32 },
+    PinsNotConsecutive { bck: u8, lrck: u8 },
+    PinsOverlap { pin: u8 },
+    NoProgramSpace,
+    PacketTooLarge { bytes: usize },
+    UnsupportedRate { hz: u32 },
+    UnsupportedFraming,
+    RateOutOfTolerance { ppm: f32 },
+}
+
+/// # Purpose
+/// Why a WAV file was rejected by `WavClip::parse` instead of being played as garbage.
+/// # Members
+/// - NotWave:              the file does not start with a RIFF header of form type `WAVE`
+/// - Truncated:            a chunk claims more bytes than the file has left
+/// - MissingChunk:         the `fmt ` or `data` chunk, named by `id`, is not in the file
+/// - UnsupportedEncoding:  the format tag is not 1, plain integer PCM
+/// - UnsupportedChannels:  the file is neither mono nor stereo
+/// - UnsupportedBitDepth:  the samples are not 16, 24 or 32 bit
+/// - UnsupportedRate:      no `SampleFrequency` preset runs at the file's rate in Hertz
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WavError {
+    NotWave,
+    Truncated,
+    MissingChunk { id: [u8; 4] },
+    UnsupportedEncoding { format_tag: u16 },
+    UnsupportedChannels { channels: u16 },
+    UnsupportedBitDepth { bits: u16 },
+    UnsupportedRate { wav_hz: u32 },
+}
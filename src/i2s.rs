@@ -0,0 +1,2059 @@
+//! The I2S output driver built on a PIO state machine.
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::digital::v2::OutputPin;
+use rp2040_hal::dma::SingleChannel;
+use rp2040_hal::multicore::{Core, Error as MulticoreError};
+use rp2040_hal::gpio::{DynPinId, FunctionPio0, FunctionPio1, FunctionSioOutput, Pin, PinId, PullDown, PullType};
+use rp2040_hal::pio::{
+    Buffers, MovStatusConfig, PIOExt, PinDir, PinState, PioIRQ, Running, ShiftDirection, StateMachine, Stopped, Tx,
+    UninitStateMachine, PIO, SM0, SM1,
+};
+
+use crate::core1::{feed_loop, RingConsumer};
+use crate::dma::{DmaLoop, DmaStream};
+use crate::dsp::{apply_q15_gain, apply_q15_gain_dithered, db_to_gain, Dither, Envelope, EnvelopeStage, TruePeakLimiter, UNITY_GAIN_Q15};
+use crate::irq::IrqFeed;
+use crate::pll::PllMonitor;
+use crate::error::I2sError;
+use crate::mclk::{Mclk, MclkConfig};
+use crate::rates::{
+    bck_divisor, compute_divisor, divisor_error_ppm, mclk_divisor, BckRatio, SampleFrequency, DAC_RATE_TOLERANCE_PPM,
+    DATA_CYCLES_PER_BCK,
+};
+use crate::sample::{i16_to_sample, pack_sample, unpack_sample, BitDepth, BitOrder, CHANNELS};
+use crate::synth::{dtmf_tones, sine, AMPLITUDE, DTMF_GAP_MS, DTMF_TONE_MS, PI};
+use crate::wav::WavClip;
+
+// Longest fade `fade_in` and `fade_out` take, in milliseconds, longer ones are cut to this
+pub const MAX_FADE_MS: u32 = 60_000;
+// Default length of the fade applied to the end of a one-shot clip in frames, 1ms at 192khz
+pub const DEFAULT_FADE_OUT_FRAMES: usize = 192;
+// How long `stop` keeps the clocks running on silence before halting, a margin for the DAC's
+// own mute ramp
+pub const DEFAULT_STOP_DELAY_MS: u32 = 5;
+// How long the DAC is given to relock to BCK when a clock gated auto-mute is released
+pub const DEFAULT_RELOCK_DELAY_MS: u32 = 20;
+// How many frames a volume change ramps over by default, 1ms at 192khz
+pub const DEFAULT_VOLUME_RAMP_FRAMES: u32 = 192;
+// Depth of one unjoined PIO fifo, joining doubles it
+pub const FIFO_DEPTH: usize = 4;
+// Offset of the I2S programs' entry point, the last bit of a silent right slot
+const ENTRY_POINT: u8 = 22;
+// USB full speed frames per second, one isochronous packet is sent per frame
+pub const USB_FRAMES_PER_SECOND: u32 = 1000;
+// How many times a write may spin on a full tx fifo before `OutputStats::long_waits` counts it,
+// a few milliseconds at 125MHz, far longer than the state machine takes to pull a word at any rate
+pub const DEFAULT_STALL_SPINS: u32 = 100_000;
+// Size of one frame of the UAC2 stream in bytes, `CHANNELS` 24 bit subslots of 3 bytes. This is
+// fixed by the descriptors `Uac2Speaker` sends, whatever the output runs at, a mismatch shows up
+// as failed enumeration or glitches. `I2sOutput::bytes_per_frame` is the output's own frame size.
+pub const BYTES_PER_FRAME: usize = CHANNELS * 3;
+
+/// # Purpose
+/// How the 4 deep tx and rx fifos of a state machine are arranged. Every state machine has both,
+/// and joining gives one direction all 8 entries at the cost of disabling the other.
+/// # Members
+/// - Separate: 4 deep tx and 4 deep rx fifos
+/// - JoinTx:   8 deep tx fifo, the rx side of that state machine is disabled
+/// - JoinRx:   8 deep rx fifo, the tx side of that state machine is disabled
+///
+/// `JoinRx` is for a capture state machine such as `I2sInput`'s, where the extra depth gives the
+/// reader more time at high rates. An output has no use for it and treats it as `Separate`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FifoJoin {
+    Separate,
+    JoinTx,
+    JoinRx,
+}
+
+impl FifoJoin {
+    /// # Purpose
+    /// The fifo arrangement in the form the `PIOBuilder` takes.
+    pub fn buffers(&self) -> Buffers {
+        match self {
+            FifoJoin::Separate => Buffers::RxTx,
+            FifoJoin::JoinTx => Buffers::OnlyTx,
+            FifoJoin::JoinRx => Buffers::OnlyRx,
+        }
+    }
+
+    /// # Purpose
+    /// The fifo arrangement an output state machine should use, which never joins towards rx.
+    pub fn for_output(self) -> FifoJoin {
+        match self {
+            FifoJoin::JoinRx => FifoJoin::Separate,
+            other => other,
+        }
+    }
+
+    /// # Purpose
+    /// How many words the tx fifo holds in this arrangement.
+    pub fn tx_depth(&self) -> usize {
+        match self {
+            FifoJoin::Separate => FIFO_DEPTH,
+            FifoJoin::JoinTx => 2 * FIFO_DEPTH,
+            FifoJoin::JoinRx => 0,
+        }
+    }
+}
+
+/// # Purpose
+/// Where each channel's data sits in its LRCK half period, i.e. the framing the DAC is told to
+/// expect, which has to match how the DAC is strapped or configured.
+/// # Members
+/// - I2s:            LRCK low for left, the MSB one BCK after each LRCK edge
+/// - LeftJustified:  LRCK high for left, the MSB on the LRCK edge itself
+/// - RightJustified: LRCK high for left, the 24 bit sample's LSB on the last BCK of the slot
+///
+/// # On the wire
+/// For a left sample `0x40_0001` in 32 bit slots, counting BCK rising edges from the LRCK edge
+/// that starts the left slot as 0, the 1 bits are sent on edges:
+/// - I2s:            2 and 24, the 8 zero bits after the sample run 1 BCK into the right slot
+/// - LeftJustified:  1 and 23, the whole word 1 BCK earlier than `I2s`
+/// - RightJustified: 9 and 31, the whole word 8 BCK later than `LeftJustified`
+///
+/// With 16 bit slots (`BckRatio::Ratio32`) only the top 16 bits of the sample fit and fill the
+/// slot, so `RightJustified` is the same as `LeftJustified` there. `RightJustified` moves each word
+/// in `I2sOutput::write_sample`, which every CPU write goes through. DMA sends buffers unchanged,
+/// so their words have to be converted with `BitOrder::right_justify` beforehand.
+///
+/// # PCM510xA strapping
+/// The PCM510xA reads the format from its FMT pin and the word length from the BCK ratio:
+/// - FMT low:  `I2s`, the default this driver is built with
+/// - FMT high: `LeftJustified`
+///
+/// 32fs BCK (`BckRatio::Ratio32`) gives 16 bit words and 64fs gives 24 or 32 bit words in either
+/// format. It has no right justified mode, `RightJustified` is for DACs that do, e.g. set over I2C.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DataFormat {
+    I2s,
+    LeftJustified,
+    RightJustified,
+}
+
+/// # Purpose
+/// What the BCK line does while the output is stopped. This is independent of what happens to the
+/// data and LRCK lines, since the DAC's PLL and clock detection only care about BCK.
+/// # Members
+/// - Low:      halt the state machine with BCK held low
+/// - High:     halt the state machine with BCK held high
+/// - Running:  keep the state machine running and clock out silence, so BCK and LRCK never stop
+///
+/// # Which to pick
+/// The PCM510xA locks its internal PLL to BCK and runs clock halt detection on it. Stopping BCK
+/// makes it power down, and on resume it has to relock and resync, which is heard as a pop. Use
+/// `Running` with it, which is the default. DACs clocked purely from an external MCLK, with BCK only
+/// used to shift data in, usually do not mind BCK halting and are fine with `Low`. `High` is for
+/// parts whose datasheet asks for the clock to idle high, check the timing diagrams of your part.
+#[derive(Clone, Copy, PartialEq)]
+pub enum IdleBck {
+    Low,
+    High,
+    Running,
+}
+
+/// # Purpose
+/// What the data and LRCK lines do once the state machine has halted on `stop`. Without this
+/// they keep whatever level they last had, which may be high and leave the DAC looking at a stuck
+/// line or sinking current through it.
+/// # Members
+/// - Low:      drive both lines low
+/// - High:     drive both lines high
+/// - Released: stop driving the lines and leave them as inputs, for boards with their own pulls
+///
+/// This only applies when the state machine actually halts, i.e. when `IdleBck` is `Low` or `High`.
+/// With `IdleBck::Running` they are still clocking out silence and keep driving every line. For the
+/// PCM510xA drive the lines low, which is the default. Its inputs have no defined level when
+/// floating, and low data with a halted clock reads as digital silence.
+#[derive(Clone, Copy, PartialEq)]
+pub enum IdleState {
+    Low,
+    High,
+    Released,
+}
+
+/// A GPIO driving one of the PCM510xA control inputs, XSMT (soft mute, low mutes) or DEMP
+/// (de-emphasis, high enables it). Any output pin erased with `into_dyn_pin`, e.g.
+/// `pins.gpio22.into_push_pull_output().into_dyn_pin()` for XSMT on the Pico Audio Pack. The pull
+/// down keeps the input low before the pin is driven, from reset onwards, so the DAC starts muted
+/// and without de-emphasis. Boards that tie an input in hardware simply do not pass a pin for it.
+pub type ControlPin = Pin<DynPinId, FunctionSioOutput, PullDown>;
+
+/// # Purpose
+/// The GPIO numbers of the three I2S lines driven by the PIO.
+/// # Members
+/// - data: the serial data line, driven by `out pins`
+/// - bck:  the bit clock, side set
+/// - lrck: the word select clock, side set along with BCK, so it has to be `bck + 1`
+///
+/// The fields can be filled in by hand, in which case the pins have to be set to the PIO's
+/// function separately, or taken from the pins themselves with `from_pio0`. Any layout that keeps
+/// LRCK right after BCK works, e.g. gpio9, 10 and 11 on the Pico Audio Pack, and it is checked
+/// when the output is built: the side set covers exactly those two pins, while `out pins` is set
+/// up separately from `data`, which need not be next to them.
+#[derive(Clone, Copy)]
+pub struct I2sPins {
+    pub data: u8,
+    pub bck: u8,
+    pub lrck: u8,
+}
+
+impl I2sPins {
+    /// # Purpose
+    /// Takes the GPIO numbers from three pins already set to `FunctionPio0`, e.g.
+    /// `pins.gpio9.into_function()`, so the numbers the program runs on can not drift from the
+    /// pins actually handed to the PIO. The pins are consumed, which keeps anything else from
+    /// reconfiguring them while the output runs.
+    ///
+    /// Fails with `I2sError::PinsNotConsecutive` unless `lrck` is the pin right after `bck`, which
+    /// the side set needs. `data` can be any other pin, and is rejected with
+    /// `I2sError::PinsOverlap` if it is one of the two clocks.
+    pub fn from_pio0<D: PinId, B: PinId, L: PinId, PD: PullType, PB: PullType, PL: PullType>(
+        data: Pin<D, FunctionPio0, PD>,
+        bck: Pin<B, FunctionPio0, PB>,
+        lrck: Pin<L, FunctionPio0, PL>,
+    ) -> Result<Self, I2sError> {
+        let pins = I2sPins {
+            data: data.id().num,
+            bck: bck.id().num,
+            lrck: lrck.id().num,
+        };
+        pins.check()?;
+        Ok(pins)
+    }
+
+    /// # Purpose
+    /// Takes the GPIO numbers from three pins set to `FunctionPio1`, as `from_pio0` does for PIO0,
+    /// e.g. for an `I2sInput` next to an output on PIO0. Fails as `from_pio0` does.
+    pub fn from_pio1<D: PinId, B: PinId, L: PinId, PD: PullType, PB: PullType, PL: PullType>(
+        data: Pin<D, FunctionPio1, PD>,
+        bck: Pin<B, FunctionPio1, PB>,
+        lrck: Pin<L, FunctionPio1, PL>,
+    ) -> Result<Self, I2sError> {
+        let pins = I2sPins {
+            data: data.id().num,
+            bck: bck.id().num,
+            lrck: lrck.id().num,
+        };
+        pins.check()?;
+        Ok(pins)
+    }
+
+    /// # Purpose
+    /// Checks LRCK is the pin right after BCK, as the two are side set together, and that data is
+    /// on a pin of its own.
+    pub(crate) fn check(&self) -> Result<(), I2sError> {
+        if self.lrck != self.bck.wrapping_add(1) {
+            return Err(I2sError::PinsNotConsecutive { bck: self.bck, lrck: self.lrck });
+        }
+        if self.data == self.bck || self.data == self.lrck {
+            return Err(I2sError::PinsOverlap { pin: self.data });
+        }
+        Ok(())
+    }
+}
+
+/// # Purpose
+/// The I2S state machine in either of its states.
+enum Machines<P: PIOExt> {
+    Running(StateMachine<(P, SM0), Running>),
+    Stopped(StateMachine<(P, SM0), Stopped>),
+}
+
+/// # Purpose
+/// Owns the PIO state machine that generates the I2S signals and the tx fifo feeding it.
+///
+/// This only touches the PIO block and the three pins in `I2sPins`. Anything board specific, such
+/// as a status LED, is left to the caller.
+///
+/// # Lifecycle
+/// `new` returns a running output. `stop` drains the fifo and halts the state machine, `start`
+/// restarts it at the beginning of a frame. `play_oneshot` starts the output if needed, plays a clip with a fade-out
+/// and leaves the output stopped when it returns.
+pub struct I2sOutput<P: PIOExt> {
+    // `None` only for the duration of a start/stop transition
+    machines: Option<Machines<P>>,
+    // `None` while a `DmaLoop`, `DmaStream` or `IrqFeed` owns the fifo, or for good once core1 does
+    tx: Option<Tx<(P, SM0)>>,
+    // the ratio asked for, kept so `set_sample_frequency` can resolve `BckRatio::Auto` again
+    requested_bck_ratio: BckRatio,
+    bck_ratio: BckRatio,
+    data_format: DataFormat,
+    bit_order: BitOrder,
+    lrck_freq: f32,
+    sys_clk: f32,
+    // the whole and fractional parts the clock divider was programmed with
+    divisor: (u16, u8),
+    pins: I2sPins,
+    // where the program starts, to send the state machine back to the first frame in `start`
+    program_offset: u8,
+    tx_depth: usize,
+    idle_bck: IdleBck,
+    idle_state: IdleState,
+    stop_delay_ms: u32,
+    fade_out_frames: usize,
+    words_written: u64,
+    // the fifo has held words since it was last drained on purpose, so finding it empty now
+    // means the producer fell behind
+    fifo_primed: bool,
+    stats: OutputStats,
+    stall_spins: u32,
+    // consecutive all-zero frames seen by `write_stereo`
+    silent_frames: u32,
+    auto_mute_frames: Option<u32>,
+    gate_bck: bool,
+    relock_delay_ms: u32,
+    auto_muted: bool,
+    clock_gated: bool,
+    // Q15 gain applied ahead of the limiters and the one it ramps towards, see `set_volume`
+    volume: u16,
+    target_volume: u16,
+    // Q15 change per frame while ramping, the sign gives the direction
+    volume_step: i32,
+    volume_ramp_frames: u32,
+    // fade in and out on top of the volume, see `set_envelope_frames`. Armed by `new` and
+    // `start`, the attack begins on the next frame written
+    envelope: Envelope,
+    envelope_armed: bool,
+    // the last frame given to `write_stereo`, which `stop` holds to finish a release
+    last_frame: (i32, i32),
+    // `None` while dither is off, see `set_dither`
+    dither: Option<Dither>,
+    limiters: [TruePeakLimiter; 2],
+    // hard clamp applied after the limiters, full scale when disabled
+    max_amplitude: i32,
+    pll_monitor: Option<PllMonitor>,
+    // XSMT, held low once a mute has ramped the volume down
+    mute_pin: Option<ControlPin>,
+    // DEMP, high while running at 44.1khz
+    deemphasis_pin: Option<ControlPin>,
+    // the master clock on sm1, for DACs that need one
+    mclk: Option<Mclk<P>>,
+    // the volume to ramp back up to on `unmute`
+    unmuted_volume: u16,
+    muted: bool,
+}
+
+/// # Purpose
+/// One stereo frame of packed fifo words, left word first. `repr(C)` so a slice of frames has the
+/// same layout as the interleaved `[u32]` the tx fifo is fed with.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Frame {
+    pub left: u32,
+    pub right: u32,
+}
+
+/// # Purpose
+/// Glitch counters for the writes that go through `I2sOutput::write_sample`, which every CPU
+/// write does, see `I2sOutput::stats`.
+/// # Members
+/// - near_misses:  writes that found the tx fifo already drained while the output was playing
+/// - long_waits:   writes that spun on a full fifo for more than the stall threshold
+///
+/// A near miss means the producer came late: the osr was still shifting out the last word when
+/// the write arrived, so it may have made it in time or the state machine may have sent a silent
+/// frame instead. It is an upper bound on the underruns, not a count of them. A long wait
+/// means the state machine stopped pulling words, see `I2sOutput::set_stall_threshold`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutputStats {
+    pub near_misses: u32,
+    pub long_waits: u32,
+}
+
+/// # Purpose
+/// A pair of frame buffers where one is being transmitted (active) while the producer fills the
+/// other in place, avoiding a copy through an intermediate sample array.
+///
+/// # Ownership
+/// - `fill_view` hands out `&mut` to the fillable buffer only, never to the active one, and the
+///   view borrows the whole `DoubleBuffer` so nothing else can touch either buffer while it lives.
+/// - `commit` hands the filled buffer over to the consumer. From then until the consumer calls
+///   `swap`, `fill_view` returns `None`, so a committed buffer can not be written while it waits to
+///   be played and can not alias the buffer being played.
+/// - `swap` is called by the consumer when it has finished with the active buffer. If a buffer was
+///   committed it becomes active and the old active buffer becomes fillable. If not, the active
+///   buffer stays put and `swap` returns false, the producer fell behind.
+pub struct DoubleBuffer<const N: usize> {
+    buffers: [[Frame; N]; 2],
+    active: usize,
+    committed: bool,
+}
+
+impl<const N: usize> DoubleBuffer<N> {
+    /// # Purpose
+    /// Two silent buffers, nothing committed yet.
+    pub fn new() -> Self {
+        DoubleBuffer {
+            buffers: [[Frame::default(); N]; 2],
+            active: 0,
+            committed: false,
+        }
+    }
+
+    /// # Purpose
+    /// The buffer the producer may fill, or `None` if it has been committed and not yet swapped in.
+    pub fn fill_view(&mut self) -> Option<&mut [Frame]> {
+        if self.committed {
+            return None;
+        }
+        Some(&mut self.buffers[1 - self.active])
+    }
+
+    /// # Purpose
+    /// Marks the fillable buffer as ready to play.
+    pub fn commit(&mut self) {
+        self.committed = true;
+    }
+
+    /// # Purpose
+    /// The buffer currently being played.
+    pub fn active(&self) -> &[Frame] {
+        &self.buffers[self.active]
+    }
+
+    /// # Purpose
+    /// Moves a committed buffer in to be played. Returns false if nothing was committed.
+    pub fn swap(&mut self) -> bool {
+        if !self.committed {
+            return false;
+        }
+        self.active = 1 - self.active;
+        self.committed = false;
+        true
+    }
+}
+
+impl<const N: usize> Default for DoubleBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Purpose
+/// Loops over a packed wavetable and lets the table be swapped for another while playing.
+///
+/// `set_wavetable` only records the new table as pending. The swap happens when the current table
+/// wraps around to its start, so a table is never left mid-sample or mid-frame (as long as its
+/// length is a whole number of frames) and the new table always starts from its first word.
+/// Tables should have matching lengths, or at least each hold whole periods, for the loop to stay
+/// seamless across the swap. Content that is not perfectly loopable can have its seam masked
+/// with `set_loop_gap`. `crossfade_to` switches tables right away instead, mixing the two over a
+/// transition.
+pub struct WavetableSource {
+    current: &'static [u32],
+    pending: Option<&'static [u32]>,
+    position: usize,
+    loop_gap_frames: usize,
+    // silent words still to be sent before the table starts over
+    gap_remaining: usize,
+    fade: Option<Crossfade>,
+    // crossfade requested while another was running, started when that one ends
+    queued_fade: Option<(&'static [u32], usize)>,
+}
+
+/// # Purpose
+/// The outgoing side of a running crossfade in `WavetableSource`, which keeps looping on its own
+/// while its level ramps down.
+struct Crossfade {
+    from: &'static [u32],
+    from_position: usize,
+    length_frames: usize,
+    elapsed_words: usize,
+}
+
+impl WavetableSource {
+    /// # Purpose
+    /// Starts looping `table` from its first word.
+    pub fn new(table: &'static [u32]) -> Self {
+        WavetableSource {
+            current: table,
+            pending: None,
+            position: 0,
+            loop_gap_frames: 0,
+            gap_remaining: 0,
+            fade: None,
+            queued_fade: None,
+        }
+    }
+
+    /// # Purpose
+    /// Queues `table` to replace the current table at the next loop boundary. Queuing another
+    /// table before the swap replaces the pending one.
+    pub fn set_wavetable(&mut self, table: &'static [u32]) {
+        self.pending = Some(table);
+    }
+
+    /// # Purpose
+    /// Inserts `frames` silent frames every time the table wraps around, masking a small
+    /// discontinuity at the seam of content that does not loop perfectly. It costs nothing per
+    /// sample, unlike a crossfade, but the seam is heard as a brief dropout instead: a few frames
+    /// pass as a click-free gap, longer gaps become an audible stutter in sustained content. A
+    /// crossfade avoids the silence but smears the end of the table into its start. Zero disables
+    /// the gap, which is the default.
+    pub fn set_loop_gap(&mut self, frames: usize) {
+        self.loop_gap_frames = frames;
+    }
+
+    /// # Purpose
+    /// Switches to `table` now, crossfading from the current table over `duration_ms` at
+    /// `sample_rate` instead of cutting. Both tables keep looping during the transition, the
+    /// new one from its first word, with their levels ramping linearly in opposite directions.
+    /// A table queued with `set_wavetable` is dropped. A `duration_ms` too short for a single
+    /// frame switches straight away.
+    ///
+    /// While the crossfade runs every word costs two table reads, unpacking both words, a 64 bit
+    /// multiply-add and packing the result, instead of a single table read. The packing and
+    /// unpacking bit reversals dominate that. At 192khz there are only a few hundred cycles per word
+    /// at 125MHz, so keep other work on the core light during the overlap, or the fifo can run dry.
+    ///
+    /// A crossfade requested while another is still running is queued and starts once that one
+    /// ends, so the running transition is never cut short with a jump. Requesting another before
+    /// the queued one starts replaces it. `is_crossfading` tells whether a crossfade is running.
+    pub fn crossfade_to(&mut self, table: &'static [u32], duration_ms: u32, sample_rate: f32) {
+        let length_frames = (sample_rate * duration_ms as f32 / 1000.0) as usize;
+        if self.fade.is_some() {
+            self.queued_fade = Some((table, length_frames));
+        } else {
+            self.start_crossfade(table, length_frames);
+        }
+    }
+
+    /// # Purpose
+    /// Returns true while a crossfade is mixing two tables.
+    pub fn is_crossfading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    /// # Purpose
+    /// Makes `table` current from its first word and fades the old current table out over
+    /// `length_frames`.
+    fn start_crossfade(&mut self, table: &'static [u32], length_frames: usize) {
+        self.pending = None;
+        if length_frames > 0 {
+            self.fade = Some(Crossfade {
+                from: self.current,
+                from_position: self.position,
+                length_frames,
+                elapsed_words: 0,
+            });
+        }
+        self.current = table;
+        self.position = 0;
+        self.gap_remaining = 0;
+    }
+
+    /// # Purpose
+    /// The next word to send to the tx fifo, swapping in a pending table at the loop boundary and
+    /// mixing in the outgoing table during a crossfade.
+    pub fn next_word(&mut self) -> u32 {
+        let word = self.next_current_word();
+        let Some(fade) = self.fade.as_mut() else {
+            return word;
+        };
+
+        let from_word = if fade.from.is_empty() {
+            pack_sample(0)
+        } else {
+            let from_word = fade.from[fade.from_position];
+            fade.from_position = (fade.from_position + 1) % fade.from.len();
+            from_word
+        };
+
+        // both channels of a frame share a gain, which runs from 1/length up to 1 for the new table
+        let length = fade.length_frames as i64;
+        let gain = (fade.elapsed_words / CHANNELS) as i64 + 1;
+        let mixed = (unpack_sample(word) as i64 * gain
+            + unpack_sample(from_word) as i64 * (length - gain))
+            / length;
+
+        fade.elapsed_words += 1;
+        if fade.elapsed_words == CHANNELS * fade.length_frames {
+            self.fade = None;
+            if let Some((table, length_frames)) = self.queued_fade.take() {
+                self.start_crossfade(table, length_frames);
+            }
+        }
+        pack_sample(mixed as i32)
+    }
+
+    /// # Purpose
+    /// The next word of the current table, handling the loop gap and pending swaps.
+    fn next_current_word(&mut self) -> u32 {
+        if self.gap_remaining > 0 {
+            self.gap_remaining -= 1;
+            return pack_sample(0);
+        }
+        if self.position == 0 {
+            if let Some(table) = self.pending.take() {
+                self.current = table;
+            }
+        }
+        if self.current.is_empty() {
+            return pack_sample(0);
+        }
+
+        let word = self.current[self.position];
+        self.position = (self.position + 1) % self.current.len();
+        if self.position == 0 {
+            self.gap_remaining = CHANNELS * self.loop_gap_frames;
+        }
+        word
+    }
+}
+
+/// # Purpose
+/// Collects the configuration of an `I2sOutput` before it is built.
+/// The BCK ratio defaults to `BckRatio::Auto`, which is 64fs at every rate but 384k, the fifos
+/// to `FifoJoin::Separate`, the framing to `DataFormat::I2s` and the word format to
+/// `BitOrder::Reversed`.
+pub struct I2sOutputBuilder {
+    pins: I2sPins,
+    sample_frequency: SampleFrequency,
+    sys_clk: f32,
+    bck_ratio: BckRatio,
+    fifo_join: FifoJoin,
+    data_format: DataFormat,
+    bit_order: BitOrder,
+    mute_pin: Option<ControlPin>,
+    deemphasis_pin: Option<ControlPin>,
+}
+
+impl I2sOutputBuilder {
+    /// # Purpose
+    /// Starts configuring an output on `pins` running at `sample_frequency`. `sys_clk_hz` is the
+    /// system clock the PIO runs from as configured, i.e. `clocks.system_clock.freq().to_Hz()`
+    /// after `init_clocks_and_plls`, so the divisors stay right for any PLL setup or overclock.
+    pub fn new(pins: I2sPins, sample_frequency: SampleFrequency, sys_clk_hz: u32) -> Self {
+        I2sOutputBuilder {
+            pins,
+            sample_frequency,
+            sys_clk: sys_clk_hz as f32,
+            bck_ratio: BckRatio::Auto,
+            fifo_join: FifoJoin::Separate,
+            data_format: DataFormat::I2s,
+            bit_order: BitOrder::Reversed,
+            mute_pin: None,
+            deemphasis_pin: None,
+        }
+    }
+
+    /// # Purpose
+    /// Selects the BCK ratio, see `BckRatio`.
+    pub fn bck_ratio(mut self, bck_ratio: BckRatio) -> Self {
+        self.bck_ratio = bck_ratio;
+        self
+    }
+
+    /// # Purpose
+    /// Selects the BCK ratio from the bit depth of the material, see `BckRatio::for_bit_depth`, so
+    /// 16 bit content is clocked out as 16 bit slots at 32fs. Takes the place of `bck_ratio`,
+    /// whichever is called last wins.
+    ///
+    /// Each channel still takes one fifo word and samples stay in the 24 bit range, of which the
+    /// top 16 bits are sent, where `i16_to_sample` puts 16 bit PCM. Every write path and DMA buffer
+    /// works the same at either depth.
+    pub fn bit_depth(mut self, depth: BitDepth) -> Self {
+        self.bck_ratio = BckRatio::for_bit_depth(depth);
+        self
+    }
+
+    /// # Purpose
+    /// Selects how the state machine's fifos are joined, see `FifoJoin`.
+    pub fn fifo_join(mut self, fifo_join: FifoJoin) -> Self {
+        self.fifo_join = fifo_join;
+        self
+    }
+
+    /// # Purpose
+    /// Selects where the data sits relative to LRCK, see `DataFormat`.
+    pub fn data_format(mut self, data_format: DataFormat) -> Self {
+        self.data_format = data_format;
+        self
+    }
+
+    /// # Purpose
+    /// Selects how words are packed and shifted out, see `BitOrder`.
+    pub fn bit_order(mut self, bit_order: BitOrder) -> Self {
+        self.bit_order = bit_order;
+        self
+    }
+
+    /// # Purpose
+    /// Gives the output the DAC's XSMT pin. The output is built muted and stays muted until
+    /// `I2sOutput::unmute`, see `I2sOutput::set_mute_pin`.
+    pub fn mute_pin(mut self, mute_pin: ControlPin) -> Self {
+        self.mute_pin = Some(mute_pin);
+        self
+    }
+
+    /// # Purpose
+    /// Gives the output the DAC's DEMP pin, see `I2sOutput::set_deemphasis_pin`.
+    pub fn deemphasis_pin(mut self, deemphasis_pin: ControlPin) -> Self {
+        self.deemphasis_pin = Some(deemphasis_pin);
+        self
+    }
+
+    /// # Purpose
+    /// Installs the I2S program on `pio` using `sm0` and returns the started output, see
+    /// `I2sOutput::new`.
+    pub fn build<P: PIOExt>(
+        self,
+        pio: &mut PIO<P>,
+        sm0: UninitStateMachine<(P, SM0)>,
+    ) -> Result<I2sOutput<P>, I2sError> {
+        let mut output = I2sOutput::new(
+            pio,
+            sm0,
+            self.pins,
+            self.sample_frequency,
+            self.sys_clk,
+            self.bck_ratio,
+            self.fifo_join,
+            self.data_format,
+            self.bit_order,
+        )?;
+        if let Some(mute_pin) = self.mute_pin {
+            output.mute();
+            output.set_mute_pin(Some(mute_pin));
+        }
+        if let Some(deemphasis_pin) = self.deemphasis_pin {
+            output.set_deemphasis_pin(Some(deemphasis_pin));
+        }
+        Ok(output)
+    }
+
+    /// # Purpose
+    /// Builds the output as `build` does and also drives a master clock from `sm1`, for DACs that
+    /// need one, see `MclkConfig` and `Mclk`. The PCM510xA derives its clocks from BCK and does
+    /// not, so this is the only way to get MCLK.
+    ///
+    /// Fails as `build` does, and with `I2sError::DivisorOutOfRange` if the MCLK the config asks
+    /// for can not be reached at the system clock, which is checked before anything is installed.
+    /// `I2sError::NoProgramSpace` for the MCLK program leaves the I2S program installed.
+    pub fn build_with_mclk<P: PIOExt>(
+        self,
+        pio: &mut PIO<P>,
+        sm0: UninitStateMachine<(P, SM0)>,
+        sm1: UninitStateMachine<(P, SM1)>,
+        mclk: MclkConfig,
+    ) -> Result<I2sOutput<P>, I2sError> {
+        let lrck_hz = self.sample_frequency.as_hz() as f32;
+        let sys_clk = self.sys_clk;
+        mclk_divisor(sys_clk, lrck_hz, mclk.multiple)?;
+        let mut output = self.build(pio, sm0)?;
+        output.mclk = Some(Mclk::new(pio, sm1, mclk, lrck_hz, sys_clk)?);
+        Ok(output)
+    }
+}
+
+impl<P: PIOExt> I2sOutput<P> {
+    /// # Purpose
+    /// Installs the I2S program, computes the clock divisor for `sample_frequency` from the system
+    /// clock `sys_clk` (Hz) and starts the state machine. BCK, LRCK and data all come from `sm0`, so only one divisor is needed and
+    /// LRCK always flips exactly at the word boundary. `fifo_join` of `FifoJoin::JoinTx` gives the
+    /// state machine an 8 deep tx fifo, `data_format` picks the program for the framing, see
+    /// `DataFormat`, and `bit_order` the shift direction the words are packed for, see `BitOrder`.
+    ///
+    /// Fails with `I2sError::DivisorOutOfRange` if the PIO divider can not produce the BCK for
+    /// `sample_frequency`, with `I2sError::RateOutOfTolerance` if the nearest divisor it can hold
+    /// misses the rate by more than the DAC takes, with `I2sError::PinsNotConsecutive` unless LRCK is the pin right after
+    /// BCK, since the two are side set together, with `I2sError::PinsOverlap` if data shares a pin
+    /// with either clock, and with `I2sError::NoProgramSpace` if other programs already fill the
+    /// instruction memory of `pio`. Nothing is installed on failure. The program takes 24 of the
+    /// 32 instructions, which leaves room for MCLK but not for an `I2sInput`, so an input goes on
+    /// the other PIO.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm0: UninitStateMachine<(P, SM0)>,
+        pins: I2sPins,
+        sample_frequency: SampleFrequency,
+        sys_clk: f32,
+        bck_ratio: BckRatio,
+        fifo_join: FifoJoin,
+        data_format: DataFormat,
+        bit_order: BitOrder,
+    ) -> Result<Self, I2sError> {
+        let fifo_join = fifo_join.for_output();
+
+        // PIO program to output the data, bck and lrck signals together from one state machine, so
+        // their relationship is fixed by the program itself and can not drift. This largely comes
+        // from the I2S example in the RP2040 datasheet (section 3.5.1) and pico-extras.
+        // output rate: 1 bit / 2 clock cycles => 0.5bits/cycle
+        //
+        // Data changes while bck is low and bck rises on the next cycle, so the DAC samples on a
+        // rising edge with the data already settled. The last bit of each channel goes out as lrck
+        // flips, which puts the MSB of the next channel one bck after the lrck edge as I2S wants.
+        // The fifo is read a whole frame at a time, so a word always plays in the slot it was
+        // written for. Two bits before the end of each right slot the state machine checks the
+        // fifo for a whole frame (`mov x, status` gives all ones below 2 words). With one, the left
+        // word is pulled as the right slot ends and the right word as the left slot ends, the
+        // second pull can not come up empty as nothing else takes words out. Without one, nothing
+        // is pulled for that frame and both slots send the zeros of a cleared osr, so an empty
+        // fifo is a silent frame instead of a stall, and a half written frame waits for its right
+        // word. x keeps the verdict over the left slot. y counts the bits of a channel and is
+        // reloaded from isr, see `load_registers`. The program is entered at `ENTRY_POINT`, in the
+        // last bit of a silent right slot, so it starts with a whole frame like any other.
+        let program = match data_format {
+            DataFormat::I2s => pio_proc::pio_asm!(
+                "
+                .side_set 2                         // bck at the side set base, lrck right above it
+                                                    //        /--- lrck
+                                                    //        |/-- bck
+                next_frame:
+                    out pins, 1             side 0b00   // the right LSB as lrck falls
+                    pull noblock            side 0b01   // a whole frame is in, take the left word
+                .wrap_target
+                    out pins, 1             side 0b00
+                    mov y, isr              side 0b01
+                left:
+                    out pins, 1             side 0b00
+                    jmp y-- left            side 0b01
+                    out pins, 1             side 0b00
+                    nop                     side 0b01
+                    out pins, 1             side 0b00
+                    jmp !x left_pulled      side 0b01
+                    out pins, 1             side 0b10   // silent frame, the right word is not pulled
+                    jmp right_slot          side 0b11
+                left_pulled:
+                    out pins, 1             side 0b10
+                    pull noblock            side 0b11
+                right_slot:
+                    out pins, 1             side 0b10
+                    mov y, isr              side 0b11
+                right:
+                    out pins, 1             side 0b10
+                    jmp y-- right           side 0b11
+                    out pins, 1             side 0b10
+                    mov x, status           side 0b11
+                    out pins, 1             side 0b10
+                    jmp !x next_frame       side 0b11
+                    out pins, 1             side 0b00   // the entry point, no whole frame in yet
+                    mov osr, null           side 0b01
+                .wrap
+                "
+            )
+            .program,
+            // the same loop with lrck flipping on the MSB instead of the bit before it, and high
+            // for the left channel
+            DataFormat::LeftJustified | DataFormat::RightJustified => pio_proc::pio_asm!(
+                "
+                .side_set 2                         // bck at the side set base, lrck right above it
+                                                    //        /--- lrck
+                                                    //        |/-- bck
+                next_frame:
+                    out pins, 1             side 0b00
+                    pull noblock            side 0b01   // a whole frame is in, take the left word
+                .wrap_target
+                    out pins, 1             side 0b10   // the left MSB goes out as lrck rises
+                    mov y, isr              side 0b11
+                left:
+                    out pins, 1             side 0b10
+                    jmp y-- left            side 0b11
+                    out pins, 1             side 0b10
+                    nop                     side 0b11
+                    out pins, 1             side 0b10
+                    jmp !x left_pulled      side 0b11
+                    out pins, 1             side 0b10   // silent frame, the right word is not pulled
+                    jmp right_slot          side 0b11
+                left_pulled:
+                    out pins, 1             side 0b10
+                    pull noblock            side 0b11
+                right_slot:
+                    out pins, 1             side 0b00   // the right MSB goes out as lrck falls
+                    mov y, isr              side 0b01
+                right:
+                    out pins, 1             side 0b00
+                    jmp y-- right           side 0b01
+                    out pins, 1             side 0b00
+                    mov x, status           side 0b01
+                    out pins, 1             side 0b00
+                    jmp !x next_frame       side 0b01
+                    out pins, 1             side 0b00   // the entry point, no whole frame in yet
+                    mov osr, null           side 0b01
+                .wrap
+                "
+            )
+            .program,
+        };
+
+        let requested_bck_ratio = bck_ratio;
+        let (bck_ratio, bck_whole, bck_frac) = clock_setup(sample_frequency, sys_clk, requested_bck_ratio)?;
+
+        // lrck is side set together with bck, which needs the two pins next to each other
+        pins.check()?;
+
+        // Set up the state machine by installing our PIO program into it and get a handle to the tx fifo
+        // for transitting data to the pio from the usb line. Every channel gets a word of its own.
+        // Either `pack_sample` bit reverses the sample so shifting right sends the MSB first, or with
+        // `BitOrder::MsbFirst` the word is left as it is and shifted left, see `BitOrder`.
+        let installed = pio.install(&program).map_err(|_| I2sError::NoProgramSpace)?;
+        let program_offset = installed.offset();
+        let (mut sm, _, tx) = rp2040_hal::pio::PIOBuilder::from_program(installed)
+            .out_pins(pins.data, 1)
+            .side_set_pin_base(pins.bck)
+            .clock_divisor_fixed_point(bck_whole, bck_frac)
+            .out_shift_direction(match bit_order {
+                BitOrder::Reversed => ShiftDirection::Right,
+                BitOrder::MsbFirst => ShiftDirection::Left,
+            })
+            .buffers(fifo_join.buffers())
+            .set_mov_status_config(MovStatusConfig::Tx(CHANNELS as u8))
+            .build(sm0);
+        sm.set_pindirs([
+            (pins.data, PinDir::Output),
+            (pins.bck, PinDir::Output),
+            (pins.lrck, PinDir::Output)]);
+        load_registers(&mut sm, bck_ratio);
+        sm.exec_instruction(jmp_to(program_offset + ENTRY_POINT));
+
+        let sm = sm.start();
+
+        Ok(I2sOutput {
+            machines: Some(Machines::Running(sm)),
+            tx: Some(tx),
+            requested_bck_ratio,
+            bck_ratio,
+            data_format,
+            bit_order,
+            lrck_freq: sample_frequency.as_hz() as f32,
+            sys_clk,
+            divisor: (bck_whole, bck_frac),
+            pins,
+            program_offset,
+            tx_depth: fifo_join.tx_depth(),
+            idle_bck: IdleBck::Running,
+            idle_state: IdleState::Low,
+            stop_delay_ms: DEFAULT_STOP_DELAY_MS,
+            fade_out_frames: DEFAULT_FADE_OUT_FRAMES,
+            words_written: 0,
+            fifo_primed: false,
+            stats: OutputStats::default(),
+            stall_spins: DEFAULT_STALL_SPINS,
+            silent_frames: 0,
+            auto_mute_frames: None,
+            gate_bck: false,
+            relock_delay_ms: DEFAULT_RELOCK_DELAY_MS,
+            auto_muted: false,
+            clock_gated: false,
+            volume: UNITY_GAIN_Q15,
+            target_volume: UNITY_GAIN_Q15,
+            volume_step: 0,
+            volume_ramp_frames: DEFAULT_VOLUME_RAMP_FRAMES,
+            envelope: Envelope::default(),
+            envelope_armed: true,
+            last_frame: (0, 0),
+            dither: None,
+            limiters: [TruePeakLimiter::new(), TruePeakLimiter::new()],
+            max_amplitude: BitDepth::Bits24.full_scale(),
+            pll_monitor: None,
+            mute_pin: None,
+            deemphasis_pin: None,
+            mclk: None,
+            unmuted_volume: UNITY_GAIN_Q15,
+            muted: false,
+        })
+    }
+
+    /// # Purpose
+    /// Returns true if the state machine is currently clocking out data.
+    pub fn is_running(&self) -> bool {
+        matches!(self.machines, Some(Machines::Running(_)))
+    }
+
+    /// # Purpose
+    /// Restarts the state machine, taking the pins back as outputs if `IdleState::Released` let go
+    /// of them. Does nothing if it is already running.
+    ///
+    /// The program is sent back to its entry point and the half sent frame is dropped, so the next
+    /// word in the tx fifo starts a fresh frame in the left slot, exactly as after `new`.
+    /// The envelope's attack, if one is set, fades in the frames written from here on.
+    pub fn start(&mut self) {
+        self.machines = match self.machines.take() {
+            Some(Machines::Stopped(mut sm)) => {
+                sm.restart();
+                load_registers(&mut sm, self.bck_ratio);
+                sm.exec_instruction(jmp_to(self.program_offset + ENTRY_POINT));
+                sm.set_pindirs([
+                    (self.pins.data, PinDir::Output),
+                    (self.pins.bck, PinDir::Output),
+                    (self.pins.lrck, PinDir::Output)]);
+                self.envelope_armed = true;
+                self.fifo_primed = false;
+                Some(Machines::Running(sm.start()))
+            }
+            other => other,
+        };
+    }
+
+    /// # Purpose
+    /// Switches the output to `sample_frequency` without rebuilding it. The BCK ratio asked for
+    /// at build time is resolved again for the new rate, the divisor is recomputed and the state
+    /// machine restarted through `start`, so the first word written afterwards starts a fresh
+    /// frame in the left slot.
+    ///
+    /// Everything already in the tx fifo is played out at the old rate first (see `stop`, the
+    /// state machine is halted for the switch even with `IdleBck::Running`). An output that was
+    /// stopped is left stopped and picks the new rate up on the next `start`. On an error nothing
+    /// changes and the output keeps running at the old rate. A mute pin is held low over the
+    /// switch and a de-emphasis pin follows the new rate, as does MCLK, which fails the switch
+    /// with `I2sError::DivisorOutOfRange` if it can not reach its multiple of the new rate. BCK
+    /// fails the same ways `new` does.
+    pub fn set_sample_frequency(&mut self, sample_frequency: SampleFrequency) -> Result<(), I2sError> {
+        let lrck_hz = sample_frequency.as_hz() as f32;
+        let (bck_ratio, bck_whole, bck_frac) =
+            clock_setup(sample_frequency, self.sys_clk, self.requested_bck_ratio)?;
+        let mclk_divisor = match self.mclk.as_ref() {
+            Some(mclk) => Some(mclk.divisor_for(lrck_hz, self.sys_clk)?),
+            None => None,
+        };
+
+        // hold the DAC in mute over the switch, so neither the halt nor the new rate is heard
+        if let Some(pin) = self.mute_pin.as_mut() {
+            let _ = pin.set_low();
+        }
+        let was_running = self.is_running();
+        self.stop();
+        self.halt();
+        if let Some(Machines::Stopped(sm)) = self.machines.as_mut() {
+            sm.clock_divisor_fixed_point(bck_whole, bck_frac);
+        }
+        self.bck_ratio = bck_ratio;
+        self.lrck_freq = lrck_hz;
+        self.divisor = (bck_whole, bck_frac);
+        if let (Some(mclk), Some(divisor)) = (self.mclk.as_mut(), mclk_divisor) {
+            mclk.retune(lrck_hz, self.sys_clk, divisor);
+        }
+        self.drive_deemphasis_pin();
+
+        if was_running {
+            self.start();
+        }
+        self.drive_mute_pin();
+        Ok(())
+    }
+
+    /// # Purpose
+    /// Lets everything already in the tx fifo reach the pins, then halts the state machine and
+    /// parks BCK according to `idle_bck`. With `IdleBck::Running` the state machine is left
+    /// clocking out silence instead. Does nothing if they are already stopped.
+    ///
+    /// A fade out started with `fade_out` or `release` is finished first, so it is heard rather
+    /// than cut: the last frame written is held and faded over the release frames still left.
+    pub fn stop(&mut self) {
+        if !self.is_running() {
+            return;
+        }
+
+        // a silent frame is ramped down already, and may not even reach the fifo if BCK is gated
+        if self.tx.is_some() && self.last_frame != (0, 0) {
+            let (left, right) = self.last_frame;
+            while self.envelope.stage() == EnvelopeStage::Release {
+                self.write_stereo(left, right);
+            }
+        }
+
+        // Queue a fifo's worth of silence behind the real data and wait for it to drain, by then
+        // the last real sample has been shifted out of the osr.
+        for _ in 0..self.tx_depth {
+            self.write_sample(pack_sample(0));
+        }
+        self.drain_tx();
+
+        // With an empty fifo the program sends silent frames, so leaving the state machine running
+        // is already silence
+        if self.idle_bck == IdleBck::Running {
+            return;
+        }
+
+        // Keep the clocks running on silence for a while so the DAC can finish its own mute ramp
+        // before it loses BCK
+        let delay_words = 2 * (self.lrck_freq * self.stop_delay_ms as f32 / 1000.0) as usize;
+        for _ in 0..delay_words {
+            self.write_sample(pack_sample(0));
+        }
+        self.drain_tx();
+
+        self.halt();
+    }
+
+    /// # Purpose
+    /// Halts the state machine straight away, parking BCK low or high (low for
+    /// `IdleBck::Running`, which only matters for clock gating) and the other lines per `idle_state`.
+    fn halt(&mut self) {
+        self.machines = match self.machines.take() {
+            Some(Machines::Running(sm)) => {
+                let mut sm = sm.stop();
+                let level = match self.idle_bck {
+                    IdleBck::High => PinState::High,
+                    _ => PinState::Low,
+                };
+                sm.set_pins([(self.pins.bck, level)]);
+
+                match self.idle_state {
+                    IdleState::Low | IdleState::High => {
+                        let level = if self.idle_state == IdleState::High { PinState::High } else { PinState::Low };
+                        sm.set_pins([(self.pins.data, level), (self.pins.lrck, level)]);
+                    }
+                    IdleState::Released => {
+                        sm.set_pindirs([(self.pins.data, PinDir::Input), (self.pins.lrck, PinDir::Input)]);
+                    }
+                }
+                Some(Machines::Stopped(sm))
+            }
+            other => other,
+        };
+    }
+
+    /// # Purpose
+    /// Configures auto-mute. After `silent_frames` consecutive all-zero frames written through
+    /// `write_stereo` (or the paths built on it) the output counts as auto-muted, until the next
+    /// frame that is not silent. `None` turns auto-mute off, which is the default.
+    ///
+    /// With `gate_bck` the state machine is also halted while auto-muted, stopping BCK and LRCK.
+    /// That saves the power of toggling the clock lines and lets the DAC drop into its own standby,
+    /// but the DAC then has to relock to BCK when audio comes back. To keep the first samples after
+    /// the silence from being lost during the relock, the clocks are restarted on
+    /// `relock_delay_ms` of silence first, which delays the audio by that much. Without
+    /// `gate_bck` the clocks keep running through the silence, costing the clock power but with no
+    /// relock and no added latency.
+    pub fn set_auto_mute(&mut self, silent_frames: Option<u32>, gate_bck: bool) {
+        self.auto_mute_frames = silent_frames;
+        self.gate_bck = gate_bck;
+        if silent_frames.is_none() && self.auto_muted {
+            self.release_auto_mute();
+        }
+    }
+
+    /// # Purpose
+    /// Sets how long the clocks run on silence before audio resumes after a clock gated auto-mute.
+    /// Defaults to `DEFAULT_RELOCK_DELAY_MS`.
+    pub fn set_relock_delay_ms(&mut self, ms: u32) {
+        self.relock_delay_ms = ms;
+    }
+
+    /// # Purpose
+    /// Returns true while the output is auto-muted.
+    pub fn is_auto_muted(&self) -> bool {
+        self.auto_muted
+    }
+
+    /// # Purpose
+    /// Enters auto-mute, gating the clocks once the fifo has drained if enabled.
+    fn engage_auto_mute(&mut self) {
+        self.auto_muted = true;
+        if self.gate_bck && self.is_running() {
+            self.drain_tx();
+            self.halt();
+            self.clock_gated = true;
+        }
+    }
+
+    /// # Purpose
+    /// Leaves auto-mute, restarting gated clocks and giving the DAC time to relock.
+    fn release_auto_mute(&mut self) {
+        self.auto_muted = false;
+        if self.clock_gated {
+            self.clock_gated = false;
+            self.start();
+            let relock_words = 2 * (self.lrck_freq * self.relock_delay_ms as f32 / 1000.0) as usize;
+            for _ in 0..relock_words {
+                self.write_sample(pack_sample(0));
+            }
+        }
+    }
+
+    /// # Purpose
+    /// Sets how long, in milliseconds, `stop` keeps BCK and LRCK running on silence after the last
+    /// sample before halting the state machine, so a DAC that ramps its own mute when the data goes
+    /// quiet is not cut off mid-ramp. Zero halts as soon as the fifo has drained. The default is
+    /// `DEFAULT_STOP_DELAY_MS`. Has no effect with `IdleBck::Running`, where the clocks never stop.
+    pub fn set_stop_delay_ms(&mut self, ms: u32) {
+        self.stop_delay_ms = ms;
+    }
+
+    /// # Purpose
+    /// Sets what the data and LRCK lines do while the output is stopped, see `IdleState`. Takes
+    /// effect on the next `stop`.
+    pub fn set_idle_state(&mut self, idle_state: IdleState) {
+        self.idle_state = idle_state;
+    }
+
+    /// # Purpose
+    /// Sets what BCK does while the output is stopped, see `IdleBck`. Takes effect on the next `stop`.
+    pub fn set_idle_bck(&mut self, idle_bck: IdleBck) {
+        self.idle_bck = idle_bck;
+    }
+
+    /// # Purpose
+    /// Writes each frame's left then right word to the tx fifo.
+    pub fn write_frames(&mut self, frames: &[Frame]) {
+        for frame in frames {
+            self.write_sample(frame.left);
+            self.write_sample(frame.right);
+        }
+    }
+
+    /// # Purpose
+    /// Writes two separate channel buffers of packed words as frames, `left[n]` then `right[n]`,
+    /// and returns how many frames were written. The output always starts at the left slot and
+    /// only ever takes whole frames here, so each word lands on its own LRCK half. If the buffers
+    /// differ in length the extra words of the longer one are not written.
+    pub fn write_channels(&mut self, left: &[u32], right: &[u32]) -> usize {
+        for (left, right) in left.iter().zip(right) {
+            self.write_sample(*left);
+            self.write_sample(*right);
+        }
+        left.len().min(right.len())
+    }
+
+    /// # Purpose
+    /// Writes interleaved 16 bit (left, right) pairs, converting each to the 24 bit output format
+    /// with `i16_to_sample` so 16 bit sources like WAV or USB audio can be fed without converting
+    /// to i32 first.
+    pub fn write_i16_stereo(&mut self, frames: &[(i16, i16)]) {
+        for (left, right) in frames {
+            self.write_stereo(i16_to_sample(*left), i16_to_sample(*right));
+        }
+    }
+
+    /// # Purpose
+    /// Writes one mono 24 bit sample as a frame with the same sample on both channels, going
+    /// through the same limiting and clamping as `write_stereo`.
+    pub fn write(&mut self, sample: i32) {
+        self.write_stereo(sample, sample);
+    }
+
+    /// # Purpose
+    /// Writes one frame of 24 bit samples, left then right, after running each channel through
+    /// its true-peak limiter and the hard clamp set by `set_max_amplitude_dbfs`. For mono, see
+    /// `write`.
+    ///
+    /// The output is in the PCM510xA's I2S format: LRCK low carries the left channel and high the
+    /// right, each sample is sent MSB first starting one BCK after the LRCK edge, and the DAC
+    /// samples data on the rising edge of BCK. Each channel gets one tx fifo word, clocked out as a
+    /// 32 bit slot at 64fs or as its first 16 bits at 32fs. The DAC wants the sample MSB first and
+    /// left justified in the slot, putting it in that order is up to `pack_sample`. BCK, LRCK and
+    /// data all come from one program, which only takes whole frames from the fifo, so the left
+    /// sample always plays while LRCK is low and the right while it is high, through underruns and
+    /// gaps alike.
+    ///
+    /// Silent frames also drive auto-mute, see `set_auto_mute`.
+    pub fn write_stereo(&mut self, left: i32, right: i32) {
+        self.last_frame = (left, right);
+        if left == 0 && right == 0 {
+            self.silent_frames = self.silent_frames.saturating_add(1);
+            if let Some(threshold) = self.auto_mute_frames {
+                if !self.auto_muted && self.silent_frames >= threshold {
+                    self.engage_auto_mute();
+                }
+            }
+            // nothing is clocking while gated, so there is nowhere for silence to go
+            if self.clock_gated {
+                return;
+            }
+        } else {
+            self.silent_frames = 0;
+            if self.auto_muted {
+                self.release_auto_mute();
+            }
+        }
+
+        self.step_volume();
+        if self.envelope_armed {
+            self.envelope.trigger();
+            self.envelope_armed = false;
+        }
+        // one multiply per sample for volume and envelope together, unity times unity is exact
+        let envelope = self.envelope.next_gain();
+        let gain = if envelope == UNITY_GAIN_Q15 {
+            self.volume
+        } else {
+            ((self.volume as u32 * envelope as u32) >> 15) as u16
+        };
+        let (left, right) = match (gain == UNITY_GAIN_Q15, self.dither.as_mut()) {
+            (true, _) => (left, right),
+            (false, None) => (apply_q15_gain(left, gain), apply_q15_gain(right, gain)),
+            (false, Some(dither)) => (
+                apply_q15_gain_dithered(left, gain, dither),
+                apply_q15_gain_dithered(right, gain, dither),
+            ),
+        };
+
+        let max = self.max_amplitude;
+        let left = self.limiters[0].process(left).clamp(-max, max);
+        let right = self.limiters[1].process(right).clamp(-max, max);
+        self.write_sample(self.bit_order.pack(left));
+        self.write_sample(self.bit_order.pack(right));
+    }
+
+    /// # Purpose
+    /// Dials `digits` as DTMF: each key plays its row and column tones together on both channels for
+    /// `DTMF_TONE_MS`, followed by `DTMF_GAP_MS` of silence timed with `timer`. Characters that are
+    /// not DTMF keys, such as spaces or dashes in "555-0100", are skipped. Each tone is at half of
+    /// `AMPLITUDE` so the pair can not clip.
+    pub fn play_dtmf<D: DelayMs<u32>>(&mut self, digits: &str, timer: &mut D) {
+        let tone_samples = (self.lrck_freq * DTMF_TONE_MS as f32 / 1000.0) as usize;
+        let amplitude = (AMPLITUDE / 2) as f32;
+
+        for (low, high) in digits.chars().filter_map(dtmf_tones) {
+            let low_step = 2.0 * PI * low / self.lrck_freq;
+            let high_step = 2.0 * PI * high / self.lrck_freq;
+            let mut low_phase = 0.0;
+            let mut high_phase = 0.0;
+
+            for _ in 0..tone_samples {
+                let sample = (amplitude * (sine(low_phase) + sine(high_phase))) as i32;
+                self.write_stereo(sample, sample);
+
+                low_phase += low_step;
+                if low_phase >= 2.0 * PI {
+                    low_phase -= 2.0 * PI;
+                }
+                high_phase += high_step;
+                if high_phase >= 2.0 * PI {
+                    high_phase -= 2.0 * PI;
+                }
+            }
+
+            // an empty fifo clocks out zeros, so the gap is silent without feeding it
+            self.drain_tx();
+            timer.delay_ms(DTMF_GAP_MS);
+        }
+    }
+
+    /// # Purpose
+    /// Writes an interleaved left, right, left, right... slice of 24 bit samples and returns how
+    /// many complete frames were written. A trailing odd sample is half a frame and is dropped
+    /// rather than written, otherwise every frame after it would land with left and right swapped.
+    pub fn write_interleaved(&mut self, samples: &[i32]) -> usize {
+        let frames = samples.chunks_exact(2);
+        let count = frames.len();
+        for frame in frames {
+            self.write_stereo(frame[0], frame[1]);
+        }
+        count
+    }
+
+    /// # Purpose
+    /// Enforces a true-peak ceiling of `dbfs` (0.0 is full scale, e.g. -1.0 for the common -1dBTP)
+    /// on the sample paths (`write_stereo`, `write_i16_stereo`), see `TruePeakLimiter` for how the
+    /// inter-sample peak is estimated. `None` turns the limiter off, which is the default. Words
+    /// that are already packed (`write_sample`, `write_frames`, wavetables) are not limited.
+    pub fn set_true_peak_ceiling(&mut self, dbfs: Option<f32>) {
+        for limiter in self.limiters.iter_mut() {
+            limiter.set_ceiling(dbfs);
+        }
+    }
+
+    /// # Purpose
+    /// Sets the output volume as a linear level from 0.0 (silence) to 1.0 (unity, the default),
+    /// anything outside that range is clamped. See `set_volume_q15`, which this rounds `level` to.
+    pub fn set_volume(&mut self, level: f32) {
+        let level = level.clamp(0.0, 1.0);
+        self.set_volume_q15((level * UNITY_GAIN_Q15 as f32 + 0.5) as u16);
+    }
+
+    /// # Purpose
+    /// Sets the output volume as a Q15 gain, `UNITY_GAIN_Q15` (0x8000, the default) being unity, 0
+    /// silence and values above unity a boost of up to 2x. It scales every sample on the sample
+    /// paths (`write_stereo` and the paths built on it) ahead of the limiters and the hard clamp,
+    /// so a boost saturates at full scale instead of wrapping. Already packed words, as written by
+    /// `write_sample`, `write_channels`, the wavetable and DMA paths, are not scaled.
+    ///
+    /// The gain ramps linearly to the new value over the frames set with `set_volume_ramp_frames`
+    /// instead of jumping, which would be heard as a click or, for a control that is moved, as
+    /// zipper noise. At unity the samples pass through untouched. While muted the new volume is
+    /// only remembered, and ramped up to on `unmute`.
+    pub fn set_volume_q15(&mut self, q15_gain: u16) {
+        if self.muted {
+            self.unmuted_volume = q15_gain;
+        } else {
+            self.ramp_volume_to(q15_gain);
+        }
+    }
+
+    /// # Purpose
+    /// Starts ramping the volume to `q15_gain`, or sets it straight away without a ramp length.
+    fn ramp_volume_to(&mut self, q15_gain: u16) {
+        self.target_volume = q15_gain;
+        if self.volume_ramp_frames == 0 {
+            self.volume = q15_gain;
+            return;
+        }
+        let distance = q15_gain as i32 - self.volume as i32;
+        // at least one step per frame, so a ramp never stalls short of the target
+        let step = distance / self.volume_ramp_frames as i32;
+        self.volume_step = if step == 0 { distance.signum() } else { step };
+    }
+
+    /// # Purpose
+    /// Turns TPDF dither on or off for the rounding of scaled samples back to 24 bit, see
+    /// `apply_q15_gain_dithered`. Off by default, and only ever applied while the volume or the
+    /// envelope scales the samples, so at unity gain the output stays bit exact either way.
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.dither = enabled.then(Dither::default);
+    }
+
+    /// # Purpose
+    /// Sets how many frames a volume change takes, `DEFAULT_VOLUME_RAMP_FRAMES` by default. 0
+    /// makes changes take effect at once. A ramp already under way keeps its speed.
+    pub fn set_volume_ramp_frames(&mut self, frames: u32) {
+        self.volume_ramp_frames = frames;
+    }
+
+    /// # Purpose
+    /// Sets the fade in at the start of a stream and the fade out at its end, in frames, both 0
+    /// (off) by default. The attack fades in the first frames written after `new` or `start`,
+    /// from exactly 0 on the first frame to unity on frame `attack_frames`; `release` fades out.
+    /// The envelope multiplies the volume rather than replacing it, so a volume ramp and a fade
+    /// can run at the same time, and like the volume it only scales the sample paths
+    /// (`write_stereo` and those built on it), not already packed words.
+    pub fn set_envelope_frames(&mut self, attack_frames: u32, release_frames: u32) {
+        self.envelope.set_attack_frames(attack_frames);
+        self.envelope.set_release_frames(release_frames);
+    }
+
+    /// # Purpose
+    /// Starts the envelope's fade out, over the release frames set with `set_envelope_frames`.
+    /// Keep writing until `envelope_stage` is `EnvelopeStage::Done`, everything after that is
+    /// silent, or `stop`, which finishes it. The next `start` fades in again. `mute` ramps down on
+    /// its own ramp, multiplied with this one, so muting during a release is still click free.
+    pub fn release(&mut self) {
+        // a release before anything was written must not be undone by the pending attack
+        self.envelope_armed = false;
+        self.envelope.release();
+    }
+
+    /// # Purpose
+    /// Fades in over `ms` milliseconds, at most `MAX_FADE_MS`, from the next frame written, and on
+    /// every `start` after that. `set_envelope_frames` sets the same length in frames.
+    pub fn fade_in(&mut self, ms: u32) {
+        self.envelope.set_attack_frames(self.ms_to_frames(ms));
+        self.envelope_armed = true;
+    }
+
+    /// # Purpose
+    /// Fades out over `ms` milliseconds, at most `MAX_FADE_MS`, from the next frame written, see
+    /// `release`. A `stop` before it is over finishes it first.
+    pub fn fade_out(&mut self, ms: u32) {
+        self.envelope.set_release_frames(self.ms_to_frames(ms));
+        self.release();
+    }
+
+    /// # Purpose
+    /// Frames in `ms` milliseconds at the current rate, `ms` cut to `MAX_FADE_MS`.
+    fn ms_to_frames(&self, ms: u32) -> u32 {
+        (self.lrck_freq * ms.min(MAX_FADE_MS) as f32 / 1000.0) as u32
+    }
+
+    /// # Purpose
+    /// Returns where the envelope is, see `release`.
+    pub fn envelope_stage(&self) -> EnvelopeStage {
+        self.envelope.stage()
+    }
+
+    /// # Purpose
+    /// Moves the volume one frame's step towards its target.
+    fn step_volume(&mut self) {
+        if self.volume == self.target_volume {
+            return;
+        }
+        let next = self.volume as i32 + self.volume_step;
+        let target = self.target_volume as i32;
+        // stop on the target rather than overshooting it with the last step
+        let reached = if self.volume_step > 0 { next >= target } else { next <= target };
+        self.volume = if reached { self.target_volume } else { next as u16 };
+        if reached && self.muted {
+            self.drive_mute_pin();
+        }
+    }
+
+    /// # Purpose
+    /// Sets a hard maximum amplitude of `db` dBFS relative to the full scale of the 24 bit samples on
+    /// the sample paths (`write_stereo`, `write_i16_stereo`, `write_interleaved`). It is meant as a
+    /// safety limit to protect sensitive gear, separate from the true-peak limiter, and is applied
+    /// after all other processing. Samples beyond it are clamped, not limited smoothly, so content
+    /// that reaches it is audibly distorted. 0.0 dBFS or above is full scale, i.e. disabled, which
+    /// is the default.
+    pub fn set_max_amplitude_dbfs(&mut self, db: f32) {
+        let full_scale = BitDepth::Bits24.full_scale();
+        let max = full_scale as f32 * db_to_gain(db);
+        self.max_amplitude = if max >= full_scale as f32 { full_scale } else { max as i32 };
+    }
+
+    /// # Purpose
+    /// Returns true if the content reached the true-peak ceiling on either channel since the last
+    /// call, i.e. it is at or over full scale as far as the ceiling is concerned. Clears the flag.
+    pub fn take_true_peak_over(&mut self) -> bool {
+        let left = self.limiters[0].take_over();
+        let right = self.limiters[1].take_over();
+        left || right
+    }
+
+    /// # Purpose
+    /// Writes the next `count` words of `source` to the tx fifo.
+    pub fn play_wavetable(&mut self, source: &mut WavetableSource, count: usize) {
+        for _ in 0..count {
+            let word = source.next_word();
+            self.write_sample(word);
+        }
+    }
+
+    /// # Purpose
+    /// Plays the active buffer of `buffers` and then swaps in the committed one, if any. Returns
+    /// false if the producer had not committed a new buffer in time.
+    pub fn play_double_buffer<const N: usize>(&mut self, buffers: &mut DoubleBuffer<N>) -> bool {
+        self.write_frames(buffers.active());
+        buffers.swap()
+    }
+
+    /// # Purpose
+    /// Sets how many frames at the end of a one-shot clip are faded out to silence. Zero disables
+    /// the fade.
+    pub fn set_fade_out_frames(&mut self, frames: usize) {
+        self.fade_out_frames = frames;
+    }
+
+    /// # Purpose
+    /// Plays a WAV clip once through `write_stereo`, a mono one on both channels, returning when
+    /// its last frame is in the tx fifo. If the output runs at another rate it is switched to the
+    /// clip's with `set_sample_frequency` first, whose error is returned if that fails. The output
+    /// keeps running, so calling it again straight away loops the clip without a gap.
+    pub fn play_wav(&mut self, clip: &WavClip) -> Result<(), I2sError> {
+        let sample_frequency = clip.sample_frequency();
+        if sample_frequency.as_hz() as f32 != self.lrck_freq {
+            self.set_sample_frequency(sample_frequency)?;
+        }
+        for (left, right) in clip.frames() {
+            self.write_stereo(left, right);
+        }
+        Ok(())
+    }
+
+    /// # Purpose
+    /// Plays `samples` exactly once and stops the output, returning when the last sample has left
+    /// the pins. The last `fade_out_frames` frames are ramped linearly down to silence so the clip
+    /// ends without a click, both words of a frame getting the same gain. A trailing odd word is
+    /// half a frame and is dropped, as in `write_interleaved`. Unlike the looping example, nothing
+    /// is repeated. A clip without a whole frame is a no-op and leaves the output as it was.
+    pub fn play_oneshot(&mut self, samples: &[u32]) {
+        let frames = samples.len() / 2;
+        if frames == 0 {
+            return;
+        }
+        self.start();
+
+        let fade_len = self.fade_out_frames.min(frames);
+        let fade_start = frames - fade_len;
+        for (i, word) in samples[..frames * 2].iter().enumerate() {
+            let frame = i / 2;
+            if frame < fade_start {
+                self.write_sample(*word);
+            } else {
+                // gain runs from just under 1 down to 0 over the faded tail, one step per frame
+                let remaining = (frames - 1 - frame) as i64;
+                let faded = self.bit_order.unpack(*word) as i64 * remaining / fade_len as i64;
+                self.write_sample(self.bit_order.pack(faded as i32));
+            }
+        }
+
+        self.stop();
+    }
+
+    /// # Purpose
+    /// The BCK ratio the hardware was actually set up with, after `BckRatio::Auto` was resolved.
+    pub fn bck_ratio(&self) -> BckRatio {
+        self.bck_ratio
+    }
+
+    /// # Purpose
+    /// The order the output shifts words out in, which packed words written to it have to match.
+    pub fn bit_order(&self) -> BitOrder {
+        self.bit_order
+    }
+
+    /// # Purpose
+    /// The framing the output was built with, see `DataFormat`.
+    pub fn data_format(&self) -> DataFormat {
+        self.data_format
+    }
+
+    /// # Purpose
+    /// The pins the output drives.
+    pub fn pins(&self) -> I2sPins {
+        self.pins
+    }
+
+    /// # Purpose
+    /// Returns the LRCK frequency in Hz, i.e. the sample rate the output runs at.
+    pub fn lrck_freq(&self) -> f32 {
+        self.lrck_freq
+    }
+
+    /// # Purpose
+    /// The signed error in parts per million of the programmed clock divisor against the ideal one
+    /// for the current rate, see `divisor_error_ppm`. LRCK is divided down from BCK inside the
+    /// same state machine, so this one number is the pitch error of both clocks.
+    pub fn divisor_error_ppm(&self) -> f32 {
+        let (whole, frac) = self.divisor;
+        divisor_error_ppm(bck_divisor(self.sys_clk, self.lrck_freq, self.bck_ratio), whole, frac)
+    }
+
+    /// # Purpose
+    /// Returns true if the tx fifo has no room for another word. Always true while a DMA transfer
+    /// owns the fifo.
+    pub fn is_full(&self) -> bool {
+        self.tx.as_ref().is_none_or(|tx| tx.is_full())
+    }
+
+    /// # Purpose
+    /// Hands the tx fifo to a DMA loop that plays `buffer` continuously, leaving the CPU free for
+    /// other work, see `DmaLoop`. `buffer` holds interleaved left, right packed words, a trailing
+    /// odd word is half a frame and is not played.
+    ///
+    /// Until the loop is handed back with `stop_dma` nothing else can write the fifo, so calling
+    /// any of the write or play methods, `stop` or `set_sample_frequency` meanwhile panics.
+    pub fn start_dma<CH1: SingleChannel, CH2: SingleChannel>(
+        &mut self,
+        channels: (CH1, CH2),
+        buffer: &'static [u32],
+    ) -> DmaLoop<CH1, CH2, P> {
+        let tx = self.tx.take().expect("the tx fifo already belongs to a DMA transfer");
+        // a mute under way can not ramp any more, so it falls to the pin
+        self.drive_mute_pin();
+        let frames = buffer.len() / CHANNELS;
+        DmaLoop::start(channels, &buffer[..frames * CHANNELS], tx)
+    }
+
+    /// # Purpose
+    /// Ends `dma` once the buffer reaches its end and takes the tx fifo back, returning the two
+    /// DMA channels. Blocks for up to two passes of the buffer.
+    pub fn stop_dma<CH1: SingleChannel, CH2: SingleChannel>(&mut self, dma: DmaLoop<CH1, CH2, P>) -> (CH1, CH2) {
+        let (ch1, ch2, tx) = dma.stop();
+        self.tx = Some(tx);
+        self.fifo_primed = false;
+        self.drive_mute_pin();
+        (ch1, ch2)
+    }
+
+    /// # Purpose
+    /// Hands the tx fifo to a ping-pong DMA stream over `buffers` for audio generated on the fly,
+    /// see `DmaStream`. Nothing plays until the first buffer is committed. As with `start_dma`,
+    /// nothing else can write the fifo until the stream is handed back with `stop_dma_stream`.
+    pub fn start_dma_stream<CH1: SingleChannel, CH2: SingleChannel>(
+        &mut self,
+        channels: (CH1, CH2),
+        buffers: [&'static mut [u32]; 2],
+    ) -> DmaStream<CH1, CH2, P> {
+        let tx = self.tx.take().expect("the tx fifo already belongs to a DMA transfer");
+        self.drive_mute_pin();
+        DmaStream::new(channels, buffers, tx)
+    }
+
+    /// # Purpose
+    /// Ends `stream` once the committed buffers have played and takes the tx fifo back, returning
+    /// the two DMA channels and both buffers.
+    pub fn stop_dma_stream<CH1: SingleChannel, CH2: SingleChannel>(
+        &mut self,
+        stream: DmaStream<CH1, CH2, P>,
+    ) -> (CH1, CH2, [&'static mut [u32]; 2]) {
+        let (ch1, ch2, buffers, tx) = stream.stop();
+        self.tx = Some(tx);
+        self.fifo_primed = false;
+        self.drive_mute_pin();
+        (ch1, ch2, buffers)
+    }
+
+    /// # Purpose
+    /// Hands the tx fifo to an interrupt driven feed with a ring of `N` words, raising `irq` from
+    /// the PIO whenever the fifo has room, see `IrqFeed`. The NVIC side, e.g. unmasking
+    /// `PIO0_IRQ_0` for `PioIRQ::Irq0` on PIO0, is left to the application along with the handler.
+    /// As with `start_dma`, nothing else can write the fifo until the feed is handed back with
+    /// `stop_irq_feed`.
+    pub fn start_irq_feed<const N: usize>(&mut self, irq: PioIRQ) -> IrqFeed<P, N> {
+        let tx = self.tx.take().expect("the tx fifo already belongs to a DMA transfer or an IRQ feed");
+        self.drive_mute_pin();
+        IrqFeed::new(tx, irq)
+    }
+
+    /// # Purpose
+    /// Hands the tx fifo to core1 for good and starts it running a loop that keeps the fifo fed
+    /// from `consumer`, so core0 is left with USB and whatever produces the audio, pushing it
+    /// through the `RingProducer` of the same `SampleRing`. `stack` is core1's stack, e.g.
+    /// `unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK.mem) }` for a
+    /// `static mut CORE1_STACK: Stack<4096>`.
+    ///
+    /// Afterwards the output only controls the state machine (`start`, `stop`,
+    /// `set_sample_frequency`, mute and the like): the write paths, DMA and `stop`'s drain need
+    /// the fifo and panic. If core1 does not come up the error is returned and the fifo is lost
+    /// with the loop that was to own it.
+    pub fn spawn_core1_feed<const N: usize>(
+        &mut self,
+        core1: &mut Core,
+        stack: &'static mut [usize],
+        consumer: RingConsumer<'static, N>,
+    ) -> Result<(), MulticoreError>
+    where
+        P: 'static,
+    {
+        let tx = self.tx.take().expect("the tx fifo already belongs to a DMA transfer or an IRQ feed");
+        self.drive_mute_pin();
+        core1.spawn(stack, move || feed_loop(tx, consumer))
+    }
+
+    /// # Purpose
+    /// Masks the feed's interrupt and takes the tx fifo back. Words still in the ring are dropped,
+    /// the few already in the fifo play out.
+    pub fn stop_irq_feed<const N: usize>(&mut self, feed: IrqFeed<P, N>) {
+        self.tx = Some(feed.stop());
+        self.fifo_primed = false;
+        self.drive_mute_pin();
+    }
+
+    /// # Purpose
+    /// The tx fifo, panicking if a DMA transfer or an IRQ feed owns it.
+    fn tx(&mut self) -> &mut Tx<(P, SM0)> {
+        self.tx.as_mut().expect("the tx fifo belongs to a DMA transfer, stop it first")
+    }
+
+    /// # Purpose
+    /// Writes one already packed word to the tx fifo, waiting for room if the fifo is full. With
+    /// `DataFormat::RightJustified` in 32 bit slots the word is moved to the end of its slot here,
+    /// see `BitOrder::right_justify`.
+    ///
+    /// Words alternate left, right, left... from the first one written after `new` or `start`.
+    /// The state machine only takes a frame once both its words are in the fifo, so a left word
+    /// written on its own waits, with silence playing, until its right word follows.
+    pub fn write_sample(&mut self, sample: u32) {
+        if let Some(monitor) = self.pll_monitor.as_mut() {
+            monitor.poll();
+        }
+        let sample = if self.data_format == DataFormat::RightJustified && self.bck_ratio.slot_bits() == 32 {
+            self.bit_order.right_justify(sample)
+        } else {
+            sample
+        };
+        let playing = self.fifo_primed && self.is_running();
+        let tx = self.tx();
+        let drained = playing && tx.is_empty();
+        let mut spins: u32 = 0;
+        while tx.is_full() {
+            spins = spins.saturating_add(1);
+        }
+        tx.write(sample);
+        self.words_written += 1;
+        self.fifo_primed = true;
+        if drained {
+            self.stats.near_misses = self.stats.near_misses.saturating_add(1);
+        }
+        if spins > self.stall_spins {
+            self.stats.long_waits = self.stats.long_waits.saturating_add(1);
+        }
+    }
+
+    /// # Purpose
+    /// Waits for the tx fifo to empty on purpose, so the next write finding it empty is not
+    /// counted as a near miss. A half written frame is finished with a silent right word first,
+    /// as the state machine only ever takes whole frames and would leave it in the fifo.
+    fn drain_tx(&mut self) {
+        if self.words_written % 2 == 1 {
+            self.write_sample(pack_sample(0));
+        }
+        while !self.tx().is_empty() {}
+        self.fifo_primed = false;
+    }
+
+    /// # Purpose
+    /// Returns the glitch counters since the last call and clears them, for soak tests that want
+    /// to know whether the output ever clicked, see `OutputStats`.
+    ///
+    /// The near misses are counted in software: the PIO's sticky TXSTALL flag in FDEBUG only sets
+    /// on a blocking `pull`, and the program clocks out a silent frame instead of stalling, so the
+    /// flag never sees an underrun. Fifos drained on purpose, by `stop`, between
+    /// DTMF digits or before gating the clocks, are not counted. Only CPU writes are counted, `DmaStream` and
+    /// `IrqFeed` keep underrun counts of their own.
+    pub fn stats(&mut self) -> OutputStats {
+        core::mem::take(&mut self.stats)
+    }
+
+    /// # Purpose
+    /// Sets how many spins on a full fifo a write may take before it counts as a long wait in
+    /// `stats`. Defaults to `DEFAULT_STALL_SPINS`.
+    pub fn set_stall_threshold(&mut self, spins: u32) {
+        self.stall_spins = spins;
+    }
+
+    /// # Purpose
+    /// Attaches `monitor` to be polled once per word written, or detaches it with `None`, which is
+    /// the default. Polling is a single register read per PLL watched. See `PllMonitor` for what a
+    /// loss means and how to recover.
+    pub fn set_pll_monitor(&mut self, monitor: Option<PllMonitor>) {
+        self.pll_monitor = monitor;
+    }
+
+    /// # Purpose
+    /// Returns true if the attached `PllMonitor` saw a PLL unlocked since the last call, so the
+    /// application can mute and reinitialize. Clears the flag. Always false without a monitor.
+    pub fn take_pll_lock_lost(&mut self) -> bool {
+        self.pll_monitor.as_mut().is_some_and(|monitor| monitor.take_lock_lost())
+    }
+
+    /// # Purpose
+    /// Takes over the DAC's XSMT pin, or with `None` hands back the one held, and drives it to the
+    /// current mute state. An output built with `I2sOutputBuilder::mute_pin` starts out muted, so
+    /// the DAC stays muted through the clock setup and the state machine's first frames.
+    pub fn set_mute_pin(&mut self, mute_pin: Option<ControlPin>) -> Option<ControlPin> {
+        let old = core::mem::replace(&mut self.mute_pin, mute_pin);
+        self.drive_mute_pin();
+        old
+    }
+
+    /// # Purpose
+    /// Returns the master clock if the output was built with `I2sOutputBuilder::build_with_mclk`.
+    pub fn mclk(&self) -> Option<&Mclk<P>> {
+        self.mclk.as_ref()
+    }
+
+    /// # Purpose
+    /// Takes over the DAC's DEMP pin, or with `None` hands back the one held. The pin is driven
+    /// high, enabling the PCM510xA's 44.1khz de-emphasis filter, while the output runs at
+    /// `SampleFrequency::Freq44_1khz` and low at every other rate, and follows
+    /// `set_sample_frequency`. Only material mastered with pre-emphasis, such as some CDs, wants
+    /// the filter, so leave the pin out (or tied low) for anything else.
+    pub fn set_deemphasis_pin(&mut self, deemphasis_pin: Option<ControlPin>) -> Option<ControlPin> {
+        let old = core::mem::replace(&mut self.deemphasis_pin, deemphasis_pin);
+        self.drive_deemphasis_pin();
+        old
+    }
+
+    /// # Purpose
+    /// Drives the de-emphasis pin, if any, high at 44.1khz and low otherwise.
+    fn drive_deemphasis_pin(&mut self) {
+        let enable = self.lrck_freq as u32 == SampleFrequency::Freq44_1khz.as_hz();
+        if let Some(pin) = self.deemphasis_pin.as_mut() {
+            // pins driven through SIO can not fail
+            let _ = if enable { pin.set_high() } else { pin.set_low() };
+        }
+    }
+
+    /// # Purpose
+    /// Mutes the output without a click. The volume ramps down to zero over the volume ramp (see
+    /// `set_volume_ramp_frames`), after which the sample paths keep feeding zeros, so BCK and LRCK
+    /// never stop and the DAC stays locked. Once the ramp is down, XSMT is pulled low as well if a
+    /// mute pin is set, and the PCM510xA mutes itself on top.
+    ///
+    /// The ramp only advances with the frames written through the sample paths (`write_stereo`
+    /// and the paths built on it). Packed words are not scaled at all, so with only those or DMA
+    /// feeding the output, mute through the pin: while DMA owns the fifo, or before the first
+    /// frame is written, the volume drops to zero and the pin goes low straight away.
+    pub fn mute(&mut self) {
+        if self.muted {
+            return;
+        }
+        self.unmuted_volume = self.target_volume;
+        self.muted = true;
+        self.ramp_volume_to(0);
+        if self.tx.is_none() || self.words_written == 0 {
+            self.volume = 0;
+        }
+        self.drive_mute_pin();
+    }
+
+    /// # Purpose
+    /// Undoes `mute`: releases XSMT first, then ramps the volume back up to where it was. To unmute
+    /// without a pop, call this once the clocks have settled and the state machine is running. If
+    /// it is running and no DMA owns the tx fifo, the fifo is first topped up with whole frames of
+    /// silence, so the DAC starts from zeros rather than an empty fifo.
+    pub fn unmute(&mut self) {
+        if !self.muted {
+            return;
+        }
+        if self.is_running() && self.tx.is_some() {
+            while !self.is_full() {
+                self.write_sample(pack_sample(0));
+                self.write_sample(pack_sample(0));
+            }
+        }
+        self.muted = false;
+        self.drive_mute_pin();
+        self.ramp_volume_to(self.unmuted_volume);
+    }
+
+    /// # Purpose
+    /// Returns true from `mute` until `unmute`, including while the ramp down is still running.
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// # Purpose
+    /// Drives the mute pin, if any: low while muted and the volume is down, or DMA owns the fifo
+    /// so the ramp can not run, and high otherwise.
+    fn drive_mute_pin(&mut self) {
+        let low = self.muted && (self.volume == 0 || self.tx.is_none());
+        if let Some(pin) = self.mute_pin.as_mut() {
+            // pins driven through SIO can not fail
+            let _ = if low { pin.set_low() } else { pin.set_high() };
+        }
+    }
+
+    /// # Purpose
+    /// The number of whole frames handed to the tx fifo so far, including the silence `stop` and
+    /// `play_at` pad with. Zeros the PIO clocks out on its own while the fifo is empty (for example
+    /// while stopped with `IdleBck::Running`) are not counted.
+    pub fn frame_counter(&self) -> u64 {
+        self.words_written / 2
+    }
+
+    /// # Purpose
+    /// Returns the size of one audio frame in bytes at the resolved BCK ratio: channels times bit
+    /// depth / 8, where the depth is the 16 bits a 32fs slot carries or the 24 the sample paths
+    /// produce at 64fs. So 4 bytes at 32fs and 6 at 64fs, which matches `BYTES_PER_FRAME` only at
+    /// 64fs. A descriptor built from this describes what the output really plays.
+    pub fn bytes_per_frame(&self) -> usize {
+        let depth = match self.bck_ratio {
+            BckRatio::Ratio32 => BitDepth::Bits16,
+            BckRatio::Ratio64 | BckRatio::Auto => BitDepth::Bits24,
+        };
+        CHANNELS * depth.bits() / 8
+    }
+
+    /// # Purpose
+    /// Returns the `wMaxPacketSize` for an isochronous OUT endpoint carrying `fs_hz` audio in this
+    /// output's frame format, in bytes, see `iso_max_packet_frames` and `bytes_per_frame`.
+    pub fn max_packet_size(&self, fs_hz: u32) -> usize {
+        iso_max_packet_frames(fs_hz) * self.bytes_per_frame()
+    }
+
+    /// # Purpose
+    /// Starts `frames` at frame index `target_frame` of `frame_counter`, padding with silent frames
+    /// until then, and returns the index of the frame it actually started on. This lets a buffer
+    /// carry a presentation timestamp for syncing with external events. The granularity is one
+    /// frame. If `target_frame` has already passed, the buffer starts immediately and the returned
+    /// index says how late it is.
+    pub fn play_at(&mut self, target_frame: u64, frames: &[Frame]) -> u64 {
+        // finish a half written frame first so the count lines up with the frame boundary
+        if self.words_written % 2 == 1 {
+            self.write_sample(pack_sample(0));
+        }
+        while self.frame_counter() < target_frame {
+            self.write_sample(pack_sample(0));
+            self.write_sample(pack_sample(0));
+        }
+
+        let start = self.frame_counter();
+        self.write_frames(frames);
+        start
+    }
+}
+
+/// # Purpose
+/// Returns the most frames an isochronous OUT packet carrying `fs_hz` audio can hold.
+///
+/// A full speed device gets one packet every 1ms frame, so a packet holds fs / 1000 frames on
+/// average. For the 48khz family that is a whole number (48, 96, 192), for the 44.1khz family it
+/// is not: 44.1khz averages 44.1 frames, which the host sends as nine packets of 44 followed by
+/// one of 45, so the maximum is rounded up to 45. On top of that one more frame is allowed,
+/// because BCK is derived from the system clock rather than the USB SOF and the host may send an
+/// extra frame per packet to follow the device's feedback. The result is ceil(fs / 1000) + 1,
+/// e.g. 46 at 44.1khz.
+pub fn iso_max_packet_frames(fs_hz: u32) -> usize {
+    fs_hz.div_ceil(USB_FRAMES_PER_SECOND) as usize + 1
+}
+
+/// # Purpose
+/// Returns the `wMaxPacketSize` for an isochronous OUT endpoint carrying `fs_hz` audio in the
+/// UAC2 stream's 24 bit format, in bytes: `iso_max_packet_frames` * `BYTES_PER_FRAME`, e.g.
+/// 46 * 6 = 276 bytes at 44.1khz.
+pub fn iso_max_packet_size(fs_hz: u32) -> usize {
+    iso_max_packet_frames(fs_hz) * BYTES_PER_FRAME
+}
+
+/// # Purpose
+/// Resolves `bck_ratio` for `sample_frequency` and works out the whole and fractional clock
+/// divisor that produce its BCK from `sys_clk`. Shared by `I2sOutput::new` and
+/// `I2sOutput::set_sample_frequency`, so both always agree.
+fn clock_setup(
+    sample_frequency: SampleFrequency,
+    sys_clk: f32,
+    bck_ratio: BckRatio,
+) -> Result<(BckRatio, u16, u8), I2sError> {
+    // Find the appropriate BCK range for the desired LRCK frequency.
+    let bck_ratio = bck_ratio.resolve(sample_frequency.as_hz() as f32, sys_clk);
+
+    // clock divisor: 1/div (instructions/tick)
+    // effective clock rate of PIO: sys_clk ticks / second * (1/div) instructions / tick => CLOCK_EFF := sys_clk/div (1/seconds)
+    // effective bit rate: CLOCK_EFF / DATA_CYCLES_PER_BCK (bits/second)
+    let (whole, frac) = compute_divisor(sys_clk, DATA_CYCLES_PER_BCK, sample_frequency.bck_hz(bck_ratio))?;
+
+    // `sys_clk` is the clock as configured, so this is the LRCK the pins really run at once the
+    // divisor is quantized to 1/256ths. It is checked against the nominal rate in every build,
+    // a rate the DAC can not lock to is refused rather than played
+    let divisor = whole as f32 + frac as f32 / 256.0;
+    let achieved = sys_clk / (DATA_CYCLES_PER_BCK * divisor) / bck_ratio.multiplier().unwrap_or(64.0);
+    let nominal = sample_frequency.as_hz() as f32;
+    let ppm = (achieved - nominal) / nominal * 1E06;
+    if !(-DAC_RATE_TOLERANCE_PPM..=DAC_RATE_TOLERANCE_PPM).contains(&ppm) {
+        return Err(I2sError::RateOutOfTolerance { ppm });
+    }
+    Ok((bck_ratio, whole, frac))
+}
+
+/// # Purpose
+/// Loads the registers the I2S program expects at its entry point: x non-zero so the frame it
+/// enters in is treated as silent, a cleared osr to send as that silence, and isr = slot width -
+/// 5, which y is reloaded from for each channel (the first and the last four bits of a channel
+/// are sent outside the counted loop). isr is free for this as the program never shifts anything
+/// in.
+fn load_registers<P: PIOExt, S>(sm: &mut StateMachine<(P, SM0), S>, bck_ratio: BckRatio) {
+    sm.exec_instruction(pio::Instruction {
+        operands: pio::InstructionOperands::SET {
+            destination: pio::SetDestination::X,
+            data: 1,
+        },
+        delay: 0,
+        side_set: None,
+    });
+    sm.exec_instruction(pio::Instruction {
+        operands: pio::InstructionOperands::MOV {
+            destination: pio::MovDestination::OSR,
+            op: pio::MovOperation::None,
+            source: pio::MovSource::NULL,
+        },
+        delay: 0,
+        side_set: None,
+    });
+    sm.exec_instruction(pio::Instruction {
+        operands: pio::InstructionOperands::SET {
+            destination: pio::SetDestination::Y,
+            data: bck_ratio.slot_bits() - 5,
+        },
+        delay: 0,
+        side_set: None,
+    });
+    sm.exec_instruction(pio::Instruction {
+        operands: pio::InstructionOperands::MOV {
+            destination: pio::MovDestination::ISR,
+            op: pio::MovOperation::None,
+            source: pio::MovSource::Y,
+        },
+        delay: 0,
+        side_set: None,
+    });
+}
+
+/// # Purpose
+/// An unconditional jump to `address`, used to send a state machine back to the start of its
+/// program.
+pub(crate) fn jmp_to(address: u8) -> pio::Instruction {
+    pio::Instruction {
+        operands: pio::InstructionOperands::JMP {
+            condition: pio::JmpCondition::Always,
+            address,
+        },
+        delay: 0,
+        side_set: None,
+    }
+}
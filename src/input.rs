@@ -0,0 +1,327 @@
+//! The I2S input driver for recording from an ADC, built on a PIO state machine.
+
+use pio::{Assembler, InSource, JmpCondition, SetDestination, WaitSource};
+use rp2040_hal::dma::SingleChannel;
+use rp2040_hal::pio::{PIOExt, PinDir, Running, Rx, ShiftDirection, StateMachine, UninitStateMachine, PIO, SM2};
+
+use crate::dma::DmaCapture;
+use crate::error::I2sError;
+use crate::i2s::{jmp_to, DataFormat, FifoJoin, I2sOutput, I2sPins};
+use crate::rates::{compute_divisor, BckRatio, SampleFrequency, DATA_CYCLES_PER_BCK};
+use crate::sample::{cast_from_u32_as_i32, BitDepth, CHANNELS};
+
+// Where the capture program starts, the `left` label: one bit into the left slot, so the first
+// word pushed is a whole left word
+const ENTRY_POINT: u8 = 3;
+// Bits a following input shifts in per slot, all of a 32 bit slot after its first bit
+const FOLLOWED_BITS: u8 = 31;
+
+// Where the input's BCK and LRCK come from
+enum InputClock {
+    // sm2 generates them from the system clock
+    Master { sys_clk: f32 },
+    // sm2 watches the pins of an output, see `I2sInput::follow`
+    Follower,
+}
+
+/// # Purpose
+/// Records I2S from an ADC. Either `sm2` is the clock master and drives BCK and LRCK itself, see
+/// `new`, or it follows the clocks an `I2sOutput` drives, see `follow`, so capture and playback
+/// share one clock. Both shift the data line in on the BCK rising edge.
+///
+/// # Frame alignment
+/// Both modes assume `DataFormat::I2s` with 32 bit slots (`BckRatio::Ratio64`): LRCK low for
+/// the left channel, the MSB one BCK after each LRCK edge, which any 24 bit I2S ADC in slave mode
+/// sends, e.g. the PCM1808 with its MD pins strapped for slave. Capture always starts on a left
+/// word once a whole left slot has been seen.
+///
+/// # Words
+/// Each channel comes out of the rx fifo as one word, left first, with the MSB in bit 31 as it
+/// was shifted in. A 24 bit ADC fills the top 24 bits and leaves the rest zero, so
+/// `cast_from_u32_as_i32` with `BitDepth::Bits24` gives the sample back in the range the output
+/// paths take, which is what `read_stereo` returns. A following input only reads the first 31
+/// bits of a slot, so bit 0 of its words is always zero.
+///
+/// # Keeping up
+/// The rx fifo is joined to 8 words, 4 frames or 21us at 192khz. The state machine stalls when
+/// the fifo is full rather than dropping a word, so left and right can never swap. As the master
+/// it holds BCK low until the fifo is read, and an ADC that needs steady clocks then loses its
+/// lock and mutes for a moment. Following an output, the clocks run on and the frames in between
+/// are lost, the right word of the frame the stall ended in may come out damaged, and capture
+/// picks up again at the next left slot. Either way read at least as fast as the samples come in, e.g. with
+/// `start_dma_capture`.
+///
+/// # Pins
+/// As the master, `pins.data` is the ADC's DOUT and is set as an input, BCK and LRCK are outputs
+/// side set together as on the output, so LRCK has to be the pin right after BCK. The output's
+/// program leaves no room for the input's, so the input runs on the other PIO, with its pins from
+/// `I2sPins::from_pio1`, and as the master it needs a second pair of clock pins. A following
+/// input only reads pins, BCK and LRCK are the output's and the data line can be any GPIO, see
+/// `follow`.
+pub struct I2sInput<P: PIOExt> {
+    sm: StateMachine<(P, SM2), Running>,
+    // `None` only while a DMA capture holds the rx fifo
+    rx: Option<Rx<(P, SM2)>>,
+    pins: I2sPins,
+    lrck_freq: f32,
+    clock: InputClock,
+}
+
+impl<P: PIOExt> I2sInput<P> {
+    /// # Purpose
+    /// Installs the capture program on `pio` using `sm2` as the clock master and starts clocking
+    /// the ADC at `sample_frequency`, from the `sys_clk_hz` the system clock is configured to.
+    ///
+    /// Fails with `I2sError::DivisorOutOfRange` if the PIO divider can not produce the BCK for
+    /// `sample_frequency`, with `I2sError::PinsNotConsecutive` unless LRCK is the pin right after
+    /// BCK, with `I2sError::PinsOverlap` if data shares a pin with either clock and with
+    /// `I2sError::NoProgramSpace` if the instruction memory of `pio` is full.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm2: UninitStateMachine<(P, SM2)>,
+        pins: I2sPins,
+        sample_frequency: SampleFrequency,
+        sys_clk_hz: u32,
+    ) -> Result<Self, I2sError> {
+        let sys_clk = sys_clk_hz as f32;
+        let (whole, frac) = input_divisor(sample_frequency, sys_clk)?;
+        pins.check()?;
+
+        // The output program turned around: bck is low for one cycle and high for the next, with
+        // lrck flipping one bck before the MSB of each slot. The ADC changes its data on the
+        // falling edge, so every bit is shifted in on the cycle bck rises. A slot is shifted in
+        // from its second bit to the first bit of the next slot, which is where I2S puts the LSB
+        // of a 32 bit word, and pushed on the low cycle right after that bit. The push blocks, so
+        // a full fifo stalls the clocks instead of dropping one channel of a frame.
+        let program = pio_proc::pio_asm!(
+            "
+            .side_set 2                         // bck at the side set base, lrck right above it
+                                                //        /--- lrck
+                                                //        |/-- bck
+            .wrap_target
+                set y, 29               side 0b00   // 30 bits in the loop, the other two around it
+                in pins, 1              side 0b01   // the LSB of the right word
+                push                    side 0b00
+            left:
+                in pins, 1              side 0b01
+                jmp y-- left            side 0b00
+                in pins, 1              side 0b01
+                set y, 29               side 0b10
+                in pins, 1              side 0b11   // the LSB of the left word
+                push                    side 0b10
+            right:
+                in pins, 1              side 0b11
+                jmp y-- right           side 0b10
+                in pins, 1              side 0b11
+            .wrap
+            "
+        )
+        .program;
+
+        let installed = pio.install(&program).map_err(|_| I2sError::NoProgramSpace)?;
+        let program_offset = installed.offset();
+        let (mut sm, rx, _) = rp2040_hal::pio::PIOBuilder::from_program(installed)
+            .in_pin_base(pins.data)
+            .side_set_pin_base(pins.bck)
+            .clock_divisor_fixed_point(whole, frac)
+            .in_shift_direction(ShiftDirection::Left)
+            .buffers(FifoJoin::JoinRx.buffers())
+            .build(sm2);
+        sm.set_pindirs([
+            (pins.data, PinDir::Input),
+            (pins.bck, PinDir::Output),
+            (pins.lrck, PinDir::Output)]);
+        // enter on the second bit of the left slot with the loop counter loaded, so the first
+        // push is a whole left word rather than the tail of a right one
+        sm.exec_instruction(pio::Instruction {
+            operands: pio::InstructionOperands::SET {
+                destination: pio::SetDestination::Y,
+                data: 29,
+            },
+            delay: 0,
+            side_set: None,
+        });
+        sm.exec_instruction(jmp_to(program_offset + ENTRY_POINT));
+
+        Ok(I2sInput {
+            sm: sm.start(),
+            rx: Some(rx),
+            pins,
+            lrck_freq: sample_frequency.as_hz() as f32,
+            clock: InputClock::Master { sys_clk },
+        })
+    }
+
+    /// # Purpose
+    /// Installs a capture program on `pio` using `sm2` that runs off the BCK and LRCK `output`
+    /// drives instead of clocks of its own, reading the data line on GPIO `data`. Capture and
+    /// playback then share one clock and one frame, which is what a loopback self-test wants:
+    /// `data` can even be `output.pins().data`, reading back what the output sends with no wiring.
+    ///
+    /// The state machine never drives a pin, so `data` stays as it is and does not have to be set
+    /// to the PIO's function. As it waits on the pins rather than sharing a program with the output,
+    /// `output` can be on either PIO, be stopped and started, or switch rates. It runs at the full
+    /// system clock and needs a few cycles of each BCK half period, which leaves plenty of margin
+    /// at 64fs for all the presets at 125MHz.
+    ///
+    /// Fails with `I2sError::UnsupportedFraming` unless `output` uses `DataFormat::I2s` with
+    /// `BckRatio::Ratio64`, which it has to keep for as long as the input follows it, and with
+    /// `I2sError::NoProgramSpace` if the instruction memory of `pio` is full. The program takes 19
+    /// instructions, which do not fit next to the output's, so `pio` has to be the other PIO.
+    pub fn follow<Q: PIOExt>(
+        pio: &mut PIO<P>,
+        sm2: UninitStateMachine<(P, SM2)>,
+        data: u8,
+        output: &I2sOutput<Q>,
+    ) -> Result<Self, I2sError> {
+        if output.data_format() != DataFormat::I2s || output.bck_ratio() != BckRatio::Ratio64 {
+            return Err(I2sError::UnsupportedFraming);
+        }
+        let clocks = output.pins();
+
+        // The pins to wait on are only known now, so unlike the other programs this one is
+        // assembled at run time. Each frame starts by waiting for LRCK to fall after a right slot,
+        // which puts the input back in step after a stall. Every bit is then shifted in on a BCK
+        // rising edge from the second bit of the slot on, the first being the last bit of the
+        // word before.
+        let mut a = Assembler::<32>::new();
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        a.bind(&mut wrap_target);
+        a.wait(1, WaitSource::GPIO, clocks.lrck, false);
+        a.wait(0, WaitSource::GPIO, clocks.lrck, false);
+        capture_slot(&mut a, clocks.bck);
+        // the right slot starts at the next LRCK level, even after a stall
+        a.wait(1, WaitSource::GPIO, clocks.lrck, false);
+        capture_slot(&mut a, clocks.bck);
+        a.bind(&mut wrap_source);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+
+        let installed = pio.install(&program).map_err(|_| I2sError::NoProgramSpace)?;
+        let (sm, rx, _) = rp2040_hal::pio::PIOBuilder::from_program(installed)
+            .in_pin_base(data)
+            .clock_divisor_fixed_point(1, 0)
+            .in_shift_direction(ShiftDirection::Left)
+            .buffers(FifoJoin::JoinRx.buffers())
+            .build(sm2);
+
+        Ok(I2sInput {
+            sm: sm.start(),
+            rx: Some(rx),
+            pins: I2sPins { data, ..clocks },
+            lrck_freq: output.lrck_freq(),
+            clock: InputClock::Follower,
+        })
+    }
+
+    /// # Purpose
+    /// Switches the clocks to `sample_frequency`. The divider takes the new value on the fly, so
+    /// the frames keep their alignment, but the frame in progress is at neither rate. On an error
+    /// nothing changes. A following input takes its rate from the output, so for it this only
+    /// notes the rate the output was switched to, for `lrck_freq`.
+    pub fn set_sample_frequency(&mut self, sample_frequency: SampleFrequency) -> Result<(), I2sError> {
+        if let InputClock::Master { sys_clk } = self.clock {
+            let (whole, frac) = input_divisor(sample_frequency, sys_clk)?;
+            self.sm.clock_divisor_fixed_point(whole, frac);
+        }
+        self.lrck_freq = sample_frequency.as_hz() as f32;
+        Ok(())
+    }
+
+    /// # Purpose
+    /// Returns the LRCK frequency in Hertz, the sample rate being recorded at.
+    pub fn lrck_freq(&self) -> f32 {
+        self.lrck_freq
+    }
+
+    /// # Purpose
+    /// Returns the pins the input runs on.
+    pub fn pins(&self) -> I2sPins {
+        self.pins
+    }
+
+    /// # Purpose
+    /// The rx fifo, which a DMA capture holds while it runs.
+    fn rx(&mut self) -> &mut Rx<(P, SM2)> {
+        self.rx.as_mut().expect("the rx fifo belongs to a DMA capture")
+    }
+
+    /// # Purpose
+    /// Waits for the next word from the rx fifo and returns it, left and right in turn, see the
+    /// word format on `I2sInput`. Panics while a DMA capture holds the fifo.
+    pub fn read_sample(&mut self) -> u32 {
+        let rx = self.rx();
+        loop {
+            if let Some(word) = rx.read() {
+                return word;
+            }
+        }
+    }
+
+    /// # Purpose
+    /// Waits for the next frame and returns it as (left, right) 24 bit samples, the inverse of
+    /// `I2sOutput::write_stereo`, see the word format on `I2sInput`.
+    pub fn read_stereo(&mut self) -> (i32, i32) {
+        let left = self.read_sample();
+        let right = self.read_sample();
+        (cast_from_u32_as_i32(left, BitDepth::Bits24), cast_from_u32_as_i32(right, BitDepth::Bits24))
+    }
+
+    /// # Purpose
+    /// Fills `words` with words from the rx fifo, blocking until all of them are in. A trailing
+    /// odd word is left untouched, so the next read still starts on a left word.
+    pub fn read_frames(&mut self, words: &mut [u32]) {
+        let frames = words.len() / CHANNELS;
+        for word in &mut words[..frames * CHANNELS] {
+            *word = self.read_sample();
+        }
+    }
+
+    /// # Purpose
+    /// Hands the rx fifo to a DMA transfer on `channel` that fills `buffer` with words as they
+    /// arrive, leaving the CPU free meanwhile, see `DmaCapture`. A trailing odd word of `buffer` is
+    /// half a frame and is not filled.
+    ///
+    /// Until the capture is handed back with `finish_dma_capture` nothing else can read the fifo,
+    /// so calling `read_sample` or `read_frames` meanwhile panics.
+    pub fn start_dma_capture<CH: SingleChannel>(
+        &mut self,
+        channel: CH,
+        buffer: &'static mut [u32],
+    ) -> DmaCapture<CH, P> {
+        let rx = self.rx.take().expect("the rx fifo already belongs to a DMA capture");
+        DmaCapture::start(channel, buffer, rx)
+    }
+
+    /// # Purpose
+    /// Waits for `capture` to fill its buffer and takes the rx fifo back, returning the DMA
+    /// channel and the filled buffer.
+    pub fn finish_dma_capture<CH: SingleChannel>(&mut self, capture: DmaCapture<CH, P>) -> (CH, &'static mut [u32]) {
+        let (channel, rx, buffer) = capture.wait();
+        self.rx = Some(rx);
+        (channel, buffer)
+    }
+}
+
+/// # Purpose
+/// The PIO divisor for the input's 64fs BCK at `sample_frequency`.
+fn input_divisor(sample_frequency: SampleFrequency, sys_clk: f32) -> Result<(u16, u8), I2sError> {
+    compute_divisor(sys_clk, DATA_CYCLES_PER_BCK, sample_frequency.bck_hz(BckRatio::Ratio64))
+}
+
+/// # Purpose
+/// Emits the part of the following program that shifts in one slot once its LRCK level has been
+/// seen: skip the first BCK, shift in the other 31 bits on the rising edges and pad the word to
+/// 32 bits with a zero LSB before pushing it.
+fn capture_slot(a: &mut Assembler<32>, bck: u8) {
+    let mut bit = a.label();
+    a.wait(1, WaitSource::GPIO, bck, false);
+    a.set(SetDestination::X, FOLLOWED_BITS - 1);
+    a.bind(&mut bit);
+    a.wait(0, WaitSource::GPIO, bck, false);
+    a.wait(1, WaitSource::GPIO, bck, false);
+    a.r#in(InSource::PINS, 1);
+    a.jmp(JmpCondition::XDecrement, &mut bit);
+    a.r#in(InSource::NULL, 32 - FOLLOWED_BITS);
+    a.push(false, true);
+}
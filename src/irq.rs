@@ -0,0 +1,148 @@
+//! Interrupt driven refill of the I2S tx fifo, for applications that want to sleep between
+//! samples instead of spinning on a full fifo.
+
+use rp2040_hal::pio::{PIOExt, PioIRQ, Tx, SM0};
+
+use crate::sample::CHANNELS;
+
+/// # Purpose
+/// A ring of packed fifo words drained into the tx fifo from the PIO's TX-not-full interrupt.
+/// Started with `I2sOutput::start_irq_feed` and handed back with `I2sOutput::stop_irq_feed`.
+///
+/// # Use
+/// The feed is shared between the interrupt handler and the main loop, so it lives in a static
+/// behind a `critical_section::Mutex`:
+///
+/// - the `PIO0_IRQ_0` handler calls `on_interrupt`, which moves words from the ring into the
+///   fifo until it is full or the ring is empty
+/// - the main loop produces, `push` queues whole frames behind those already in the ring, then it
+///   sleeps with `wfi` until the next interrupt. `needs_refill` tells it whether the ring is low
+///   enough to be worth topping up on this wake, see `set_refill_threshold`
+///
+/// The interrupt is level triggered and stays asserted for as long as the fifo is not full, so
+/// `on_interrupt` masks it at the PIO once the ring runs dry and `push` unmasks it again. Each
+/// time that happens while the output was playing counts as an underrun, see `take_underruns`.
+/// If the fifo itself runs dry the state machine clocks out silence and, as only whole frames
+/// are queued, picks up again in the left slot.
+///
+/// With a ring of N frames the main loop only has to wake every N / fs, e.g. 2.7ms for 512 frames
+/// at 192khz, instead of every 4 words.
+///
+/// # Latency and interrupt rate
+/// The PIO has no fifo level interrupt for tx, only TX-not-full, so the handler runs once for
+/// every word the state machine pulls, 2 * fs times a second, whatever the threshold. Joining the
+/// fifos with `FifoJoin::JoinTx` lets each run move up to 8 words instead of 4. What the refill
+/// threshold trades is the other side: the producer only refills once the ring has drained to
+/// the threshold and then fills it to the top, so a low threshold means fewer, larger refills but
+/// less audio in hand when one is late, and a high one keeps the ring near full at the cost of
+/// refilling on almost every wake. Audio pushed into a full ring waits up to N / fs to be heard,
+/// so a smaller ring is what brings the latency down.
+pub struct IrqFeed<P: PIOExt, const N: usize> {
+    tx: Tx<(P, SM0)>,
+    irq: PioIRQ,
+    ring: [u32; N],
+    // free running read and write positions in `ring`, reduced modulo `N` on use
+    read: usize,
+    write: usize,
+    // the interrupt is masked at the PIO because the ring ran dry
+    starved: bool,
+    underruns: u32,
+    // `needs_refill` is true at or below this many words in the ring
+    refill_threshold: usize,
+}
+
+impl<P: PIOExt, const N: usize> IrqFeed<P, N> {
+    /// # Purpose
+    /// An empty feed into `tx`, raising `irq` once it has words for the fifo. `N` is the ring size
+    /// in words and has to be a power of two, so the free running positions stay valid across
+    /// wrapping.
+    pub(crate) fn new(tx: Tx<(P, SM0)>, irq: PioIRQ) -> Self {
+        assert!(N.is_power_of_two() && N >= CHANNELS, "the ring size has to be a power of two");
+        IrqFeed {
+            tx,
+            irq,
+            ring: [0; N],
+            read: 0,
+            write: 0,
+            // nothing to send yet, so the interrupt starts masked
+            starved: true,
+            underruns: 0,
+            refill_threshold: N / 2,
+        }
+    }
+
+    /// # Purpose
+    /// Words waiting in the ring.
+    fn len(&self) -> usize {
+        self.write.wrapping_sub(self.read)
+    }
+
+    /// # Purpose
+    /// Sets how far the ring drains before `needs_refill` asks for more, in frames, clamped to the
+    /// ring size. Defaults to half the ring, see the latency notes on `IrqFeed`.
+    pub fn set_refill_threshold(&mut self, frames: usize) {
+        self.refill_threshold = frames.saturating_mul(CHANNELS).min(N);
+    }
+
+    /// # Purpose
+    /// Returns true once the ring has drained to the refill threshold, so the producer can skip
+    /// wakes where topping up would only add a few frames.
+    pub fn needs_refill(&self) -> bool {
+        self.len() <= self.refill_threshold
+    }
+
+    /// # Purpose
+    /// Returns the number of whole frames `push` can take right now.
+    pub fn free_frames(&self) -> usize {
+        (N - self.len()) / CHANNELS
+    }
+
+    /// # Purpose
+    /// Queues as many whole frames of packed fifo words from `words` as fit in the ring and
+    /// returns the number of words taken, so the caller can come back with the rest. A trailing
+    /// odd word is never taken.
+    pub fn push(&mut self, words: &[u32]) -> usize {
+        let count = (words.len() / CHANNELS).min(self.free_frames()) * CHANNELS;
+        for word in &words[..count] {
+            self.ring[self.write % N] = *word;
+            self.write = self.write.wrapping_add(1);
+        }
+        if count > 0 && self.starved {
+            self.starved = false;
+            self.tx.enable_tx_not_full_interrupt(self.irq);
+        }
+        count
+    }
+
+    /// # Purpose
+    /// The interrupt handler's half: fills the fifo from the ring, and masks the interrupt if the
+    /// ring runs dry. Short enough to run on every TX-not-full interrupt.
+    pub fn on_interrupt(&mut self) {
+        while self.read != self.write && !self.tx.is_full() {
+            self.tx.write(self.ring[self.read % N]);
+            self.read = self.read.wrapping_add(1);
+        }
+        if self.read == self.write && !self.tx.is_full() {
+            self.tx.disable_tx_not_full_interrupt(self.irq);
+            if !self.starved {
+                self.starved = true;
+                self.underruns = self.underruns.saturating_add(1);
+            }
+        }
+    }
+
+    /// # Purpose
+    /// Returns the number of times the ring ran dry since the last call, and clears it.
+    pub fn take_underruns(&mut self) -> u32 {
+        let underruns = self.underruns;
+        self.underruns = 0;
+        underruns
+    }
+
+    /// # Purpose
+    /// Masks the interrupt and gives back the tx fifo, dropping anything still in the ring.
+    pub(crate) fn stop(mut self) -> Tx<(P, SM0)> {
+        self.tx.disable_tx_not_full_interrupt(self.irq);
+        self.tx
+    }
+}
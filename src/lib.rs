@@ -0,0 +1,19 @@
+//! I2S audio output for the RP2040 using PIO, written for the PCM510xA DAC on the Pimoroni Pico
+//! Audio Pack. The boot block and entry point live in the example binary, see `examples/pio_audio.rs`.
+#![no_std]
+
+pub mod core1;
+pub mod dma;
+pub mod dsp;
+pub mod error;
+pub mod i2s;
+pub mod input;
+pub mod irq;
+pub mod mclk;
+pub mod pll;
+pub mod pwm;
+pub mod rates;
+pub mod sample;
+pub mod synth;
+pub mod uac2;
+pub mod wav;
@@ -0,0 +1,129 @@
+//! An optional master clock output for DACs that need one alongside BCK and LRCK.
+
+use rp2040_hal::gpio::{FunctionPio0, Pin, PinId, PullType};
+use rp2040_hal::pio::{PIOExt, PinDir, Running, StateMachine, UninitStateMachine, PIO, SM1};
+
+use crate::error::I2sError;
+use crate::rates::{divisor_error_ppm, mclk_divisor, MCLK_CYCLES_PER_PERIOD};
+
+/// # Purpose
+/// Where the master clock goes and how fast it runs, see `I2sOutputBuilder::build_with_mclk`.
+/// # Members
+/// - pin:       the GPIO MCLK is driven on, any pin the PIO can reach
+/// - multiple:  MCLK as a multiple of LRCK, typically 256 or 512
+#[derive(Clone, Copy)]
+pub struct MclkConfig {
+    pub pin: u8,
+    pub multiple: u16,
+}
+
+impl MclkConfig {
+    /// # Purpose
+    /// A config for MCLK at `multiple` times LRCK on a pin already set to `FunctionPio0`, see
+    /// `I2sPins::from_pio0`.
+    pub fn from_pio0<I: PinId, PT: PullType>(pin: Pin<I, FunctionPio0, PT>, multiple: u16) -> Self {
+        MclkConfig { pin: pin.id().num, multiple }
+    }
+}
+
+/// # Purpose
+/// Owns the state machine that toggles MCLK at `MclkConfig::multiple` times LRCK.
+///
+/// It runs from the same system clock as the I2S state machine, but with a divisor of its own, so
+/// the two are only locked in frequency, not in phase. Both divisors are quantized to 1/256, and
+/// a fractional MCLK divisor repeats some system clock cycles to hit the average rate, which
+/// shows up as one system clock of jitter on the MCLK edges (8ns at 125MHz). For a clean MCLK, run
+/// the system clock at a whole multiple of 2 * MCLK, e.g. 122.88MHz for 256fs at 48khz.
+///
+/// MCLK keeps running while the I2S output is stopped, as DACs clocked from it usually want it
+/// before BCK starts and after it stops.
+pub struct Mclk<P: PIOExt> {
+    sm: StateMachine<(P, SM1), Running>,
+    config: MclkConfig,
+    freq: f32,
+    error_ppm: f32,
+}
+
+impl<P: PIOExt> Mclk<P> {
+    /// # Purpose
+    /// Installs the MCLK program on `pio` using `sm1` and starts it at `config.multiple` times
+    /// `lrck_hz`. Fails with `I2sError::DivisorOutOfRange` if that MCLK can not be reached from
+    /// `sys_clk` and with `I2sError::NoProgramSpace` if the instruction memory is full.
+    pub(crate) fn new(
+        pio: &mut PIO<P>,
+        sm1: UninitStateMachine<(P, SM1)>,
+        config: MclkConfig,
+        lrck_hz: f32,
+        sys_clk: f32,
+    ) -> Result<Self, I2sError> {
+        let (whole, frac) = mclk_divisor(sys_clk, lrck_hz, config.multiple)?;
+
+        // one MCLK period per two instructions, high for the first and low for the second
+        let program = pio_proc::pio_asm!(
+            "
+            .wrap_target
+                set pins, 1
+                set pins, 0
+            .wrap
+            "
+        )
+        .program;
+        let installed = pio.install(&program).map_err(|_| I2sError::NoProgramSpace)?;
+        let (mut sm, _, _) = rp2040_hal::pio::PIOBuilder::from_program(installed)
+            .set_pins(config.pin, 1)
+            .clock_divisor_fixed_point(whole, frac)
+            .build(sm1);
+        sm.set_pindirs([(config.pin, PinDir::Output)]);
+
+        let mut mclk = Mclk {
+            sm: sm.start(),
+            config,
+            freq: 0.0,
+            error_ppm: 0.0,
+        };
+        mclk.record(lrck_hz, sys_clk, (whole, frac));
+        Ok(mclk)
+    }
+
+    /// # Purpose
+    /// The divisor for `lrck_hz` at the configured multiple, for checking a new rate before
+    /// anything is changed.
+    pub(crate) fn divisor_for(&self, lrck_hz: f32, sys_clk: f32) -> Result<(u16, u8), I2sError> {
+        mclk_divisor(sys_clk, lrck_hz, self.config.multiple)
+    }
+
+    /// # Purpose
+    /// Reprograms the divider with a `divisor` from `divisor_for`, for a new `lrck_hz`. The
+    /// divider takes the new value on the fly, without stopping MCLK.
+    pub(crate) fn retune(&mut self, lrck_hz: f32, sys_clk: f32, divisor: (u16, u8)) {
+        self.sm.clock_divisor_fixed_point(divisor.0, divisor.1);
+        self.record(lrck_hz, sys_clk, divisor);
+    }
+
+    /// # Purpose
+    /// Returns the configuration the clock was set up with.
+    pub fn config(&self) -> MclkConfig {
+        self.config
+    }
+
+    /// # Purpose
+    /// Returns the ideal MCLK frequency in Hertz, `multiple` times LRCK.
+    pub fn freq(&self) -> f32 {
+        self.freq
+    }
+
+    /// # Purpose
+    /// Returns how far MCLK is off `freq` in parts per million from quantizing its divisor, see
+    /// `rates::divisor_error_ppm`.
+    pub fn divisor_error_ppm(&self) -> f32 {
+        self.error_ppm
+    }
+
+    /// # Purpose
+    /// Notes the frequency and divisor error for `lrck_hz` with `divisor`.
+    fn record(&mut self, lrck_hz: f32, sys_clk: f32, divisor: (u16, u8)) {
+        self.freq = lrck_hz * self.config.multiple as f32;
+        let target_div = sys_clk / MCLK_CYCLES_PER_PERIOD / self.freq;
+        self.error_ppm = divisor_error_ppm(target_div, divisor.0, divisor.1);
+    }
+}
@@ -0,0 +1,110 @@
+//! Runtime monitoring of the PLL lock status behind the audio clocks.
+
+use rp2040_hal::pac;
+
+/// # Purpose
+/// Names one of the two RP2040 PLLs.
+/// # Members
+/// - Sys:  PLL_SYS, which clocks the PIO and so BCK and LRCK
+/// - Usb:  PLL_USB, which clocks the USB controller for a UAC2 device
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pll {
+    Sys,
+    Usb,
+}
+
+impl Pll {
+    /// # Purpose
+    /// Returns true if the PLL currently reports lock. Reads only the read-only LOCK bit of its CS
+    /// register, so it is safe to call while the clock setup code owns the peripheral.
+    pub fn is_locked(self) -> bool {
+        // SAFETY: a read of a status bit, nothing is written
+        let cs = match self {
+            Pll::Sys => unsafe { (*pac::PLL_SYS::ptr()).cs().read() },
+            Pll::Usb => unsafe { (*pac::PLL_USB::ptr()).cs().read() },
+        };
+        cs.lock().bit_is_set()
+    }
+}
+
+/// # Purpose
+/// Watches the PLLs for loss of lock after the initial clock setup, e.g. from a brownout or a
+/// glitch on the crystal, which would otherwise leave the audio clocks silently wrong.
+///
+/// The monitor polls: each call to `poll` reads the LOCK bit of every watched PLL, and a PLL
+/// that is found unlocked sets a sticky flag (see `take_lock_lost`) and calls the optional
+/// callback. There is no interrupt for lock loss, so a loss is only seen on the next poll, and a
+/// loss that recovers between two polls can be missed entirely. `I2sOutput` polls once per word
+/// written when a monitor is attached, see `I2sOutput::set_pll_monitor`.
+///
+/// Recovering is up to the application: mute (`I2sOutput::stop`), bring the clocks back up with
+/// `init_clocks_and_plls` (or reset the chip through the watchdog if the clock peripherals were
+/// given away), then build a new `I2sOutput` so the divisors are reinstalled and both state
+/// machines restart in sync, which realigns LRCK with the data.
+pub struct PllMonitor {
+    watch_usb: bool,
+    lost: bool,
+    on_loss: Option<fn(Pll)>,
+}
+
+impl PllMonitor {
+    /// # Purpose
+    /// A monitor watching PLL_SYS only, with no callback.
+    pub const fn new() -> Self {
+        PllMonitor {
+            watch_usb: false,
+            lost: false,
+            on_loss: None,
+        }
+    }
+
+    /// # Purpose
+    /// Also watches PLL_USB, for setups where a UAC2 device depends on it.
+    pub fn set_watch_usb(&mut self, watch_usb: bool) {
+        self.watch_usb = watch_usb;
+    }
+
+    /// # Purpose
+    /// Sets a callback run from `poll` with the PLL that was found unlocked, or `None` to rely on
+    /// the flag alone. It runs on every poll the PLL stays unlocked, so it should be short.
+    pub fn set_on_loss(&mut self, on_loss: Option<fn(Pll)>) {
+        self.on_loss = on_loss;
+    }
+
+    /// # Purpose
+    /// Checks the watched PLLs. Returns true if all of them are locked.
+    pub fn poll(&mut self) -> bool {
+        let mut locked = self.check(Pll::Sys);
+        if self.watch_usb {
+            locked &= self.check(Pll::Usb);
+        }
+        locked
+    }
+
+    /// # Purpose
+    /// Returns true if a watched PLL was found unlocked since the last call. Clears the flag.
+    pub fn take_lock_lost(&mut self) -> bool {
+        let lost = self.lost;
+        self.lost = false;
+        lost
+    }
+
+    /// # Purpose
+    /// Checks one PLL, recording and reporting it if unlocked.
+    fn check(&mut self, pll: Pll) -> bool {
+        if pll.is_locked() {
+            return true;
+        }
+        self.lost = true;
+        if let Some(on_loss) = self.on_loss {
+            on_loss(pll);
+        }
+        false
+    }
+}
+
+impl Default for PllMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
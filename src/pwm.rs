@@ -0,0 +1,163 @@
+//! A PWM fallback output for boards without an I2S DAC, playing the same samples on one GPIO.
+
+use embedded_hal::PwmPin;
+use rp2040_hal::pwm::{FreeRunning, Slice, SliceId};
+
+use crate::error::I2sError;
+use crate::i2s::Frame;
+use crate::rates::{compute_divisor, SampleFrequency};
+use crate::sample::{unpack_sample, FULL_SCALE_24BIT};
+
+/// # Purpose
+/// Which of the two outputs of a PWM slice the audio is on.
+/// # Members
+/// - A:  the even GPIO of the slice, e.g. gpio0 for slice 0
+/// - B:  the odd GPIO of the slice, e.g. gpio1 for slice 0
+#[derive(Clone, Copy, PartialEq)]
+pub enum PwmChannel {
+    A,
+    B,
+}
+
+/// # Purpose
+/// A degraded quality stand in for `I2sOutput` that plays through a PWM slice, for hearing the
+/// tone with only an RC low pass filter and a speaker on one GPIO. The writing methods take the
+/// same samples and packed words as `I2sOutput`'s, so the generators, `SINE_FRAMES` and friends
+/// play unchanged and switching between the two is a constructor change.
+///
+/// # Output
+/// The slice wraps once per sample, so the PWM carrier is at the sample rate and each sample
+/// sets the duty cycle of one period. The counter runs from the system clock, giving
+/// sys_clk / fs steps, e.g. 2604 at 125MHz and 48khz, about 11 bits, and only 9 bits at 192khz.
+/// The 24 bit sample is scaled down to that range, silence being a 50% duty cycle. Stereo is
+/// mixed down to mono. With the carrier at the sample rate the RC filter can not remove it
+/// completely, so a low rate such as 32khz, with the filter's corner around 10khz, sounds best.
+///
+/// # Pacing
+/// Each write waits for the counter to wrap and then sets the duty of the next period, which the
+/// slice latches at the following wrap, so samples go out at exactly the rate with one period of
+/// latency. The compare register is double buffered, so a write that comes late repeats the last
+/// sample rather than glitching.
+pub struct PwmOutput<I: SliceId> {
+    slice: Slice<I, FreeRunning>,
+    channel: PwmChannel,
+    // counter steps per period, the duty that is 100% high
+    period: u32,
+    lrck_freq: f32,
+    sys_clk: f32,
+    // the left word of a frame written with `write_sample`, waiting for its right word
+    left: Option<u32>,
+}
+
+impl<I: SliceId> PwmOutput<I> {
+    /// # Purpose
+    /// Sets up `slice` to play on `channel` at `sample_frequency` from the `sys_clk_hz` the system
+    /// clock is configured to, and starts it at silence. The GPIO has to be routed to the channel
+    /// first, e.g. with `slice.channel_a.output_to(pins.gpio0)`. Fails with
+    /// `I2sError::DivisorOutOfRange` if the rate can not be reached from `sys_clk_hz`.
+    pub fn new(
+        slice: Slice<I, FreeRunning>,
+        channel: PwmChannel,
+        sample_frequency: SampleFrequency,
+        sys_clk_hz: u32,
+    ) -> Result<Self, I2sError> {
+        let mut output = PwmOutput {
+            slice,
+            channel,
+            period: 0,
+            lrck_freq: 0.0,
+            sys_clk: sys_clk_hz as f32,
+            left: None,
+        };
+        output.set_sample_frequency(sample_frequency)?;
+        output.slice.enable();
+        Ok(output)
+    }
+
+    /// # Purpose
+    /// Switches to `sample_frequency`, which takes effect at the next wrap. On an error nothing
+    /// changes.
+    pub fn set_sample_frequency(&mut self, sample_frequency: SampleFrequency) -> Result<(), I2sError> {
+        let lrck_hz = sample_frequency.as_hz() as f32;
+        // as many counter steps as fit with the divider at 1, the fractional divider takes up the
+        // rest so the rate still comes out to 1/256 of a step
+        let period = ((self.sys_clk / lrck_hz) as u32).clamp(2, u16::MAX as u32 + 1);
+        let (whole, frac) = compute_divisor(self.sys_clk, period as f32, lrck_hz)?;
+        if whole > u8::MAX as u16 {
+            return Err(I2sError::DivisorOutOfRange { divisor: whole as f32 });
+        }
+
+        self.slice.set_div_int(whole as u8);
+        self.slice.set_div_frac(frac);
+        self.slice.set_top((period - 1) as u16);
+        self.period = period;
+        self.lrck_freq = lrck_hz;
+        self.set_duty(0);
+        Ok(())
+    }
+
+    /// # Purpose
+    /// Returns the sample rate in Hertz, named as on `I2sOutput` so code written for one runs on
+    /// the other.
+    pub fn lrck_freq(&self) -> f32 {
+        self.lrck_freq
+    }
+
+    /// # Purpose
+    /// Returns the number of duty cycle steps per period, i.e. the resolution samples are scaled
+    /// down to.
+    pub fn duty_steps(&self) -> u32 {
+        self.period
+    }
+
+    /// # Purpose
+    /// Plays one 24 bit sample, waiting for the current period to end first.
+    pub fn write(&mut self, sample: i32) {
+        while !self.slice.has_overflown() {}
+        self.slice.clear_interrupt();
+        self.set_duty(sample);
+    }
+
+    /// # Purpose
+    /// Plays one frame of 24 bit samples mixed down to mono, see `write`.
+    pub fn write_stereo(&mut self, left: i32, right: i32) {
+        // the sum of two 24 bit samples fits an i32 with room to spare
+        self.write((left + right) / 2);
+    }
+
+    /// # Purpose
+    /// Takes one packed word as `I2sOutput::write_sample` does, a left word and then its right
+    /// word, and plays each frame once both have arrived, see `write_stereo`.
+    pub fn write_sample(&mut self, sample: u32) {
+        match self.left.take() {
+            None => self.left = Some(sample),
+            Some(left) => self.write_stereo(unpack_sample(left), unpack_sample(sample)),
+        }
+    }
+
+    /// # Purpose
+    /// Plays each frame of packed words, see `write_stereo`.
+    pub fn write_frames(&mut self, frames: &[Frame]) {
+        for frame in frames {
+            self.write_stereo(unpack_sample(frame.left), unpack_sample(frame.right));
+        }
+    }
+
+    /// # Purpose
+    /// Stops the slice and gives it back, with the output left at whatever it was driving.
+    pub fn free(mut self) -> Slice<I, FreeRunning> {
+        self.slice.disable();
+        self.slice
+    }
+
+    /// # Purpose
+    /// Sets the duty of the next period for `sample`, offset so silence is half the period.
+    fn set_duty(&mut self, sample: i32) {
+        let offset = sample.clamp(-FULL_SCALE_24BIT - 1, FULL_SCALE_24BIT) as i64 + FULL_SCALE_24BIT as i64 + 1;
+        let duty = ((offset * self.period as i64) >> 24) as u16;
+        match self.channel {
+            PwmChannel::A => self.slice.channel_a.set_duty(duty),
+            PwmChannel::B => self.slice.channel_b.set_duty(duty),
+        }
+    }
+}
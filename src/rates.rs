@@ -0,0 +1,357 @@
+//! Sample rates, BCK ratios and the PIO clock divisors that produce them.
+
+use crate::error::I2sError;
+use crate::sample::BitDepth;
+
+// PIO instructions executed per BCK period by the I2S program (an out with bck low, then one
+// with bck high)
+pub const DATA_CYCLES_PER_BCK: f32 = 2.0;
+// PIO instructions executed per MCLK period by the MCLK program (a set high, then a set low)
+pub const MCLK_CYCLES_PER_PERIOD: f32 = 2.0;
+// 64fs divisors below this leave the PIO with almost no slack and a fractional divisor jitters
+// by a large part of a BCK period, so `BckRatio::Auto` drops to 32fs under it
+pub const MIN_COMFORTABLE_DIVISOR: f32 = 3.0;
+// How far off its preset a rate may come out and still be locked on to by the PCM510xA, 4%
+pub const DAC_RATE_TOLERANCE_PPM: f32 = 40_000.0;
+
+/// # Purose
+/// Represents the lrck sample frequency to use, represented as its own data type to prevent
+/// comparisons to numbers where ever possible.
+/// # Members
+/// - Freq32khz:    32khz lrck signal
+/// - Freq44_1khz:  44.1khz lrck signal
+/// - Freq48khz:    48khz lrck signal
+/// - Freq96khz:    96khz lrck signal
+/// - Freq192khz:   192khz lrck signal
+/// - Freq384khz:   384khz lrck signal
+#[derive(Clone, Copy, PartialEq)]
+pub enum SampleFrequency {
+    Freq32khz,
+    Freq44_1khz,
+    Freq48khz,
+    Freq96khz,
+    Freq192khz,
+    Freq384khz,
+}
+
+/// # Purpose
+/// One row of Table 11. BCK Rates (MHz) by LRCK Sample Rate for PCM510xA PLL Operation, in Hertz.
+/// # Members
+/// - lrck:     the LRCK (sample) frequency
+/// - bck_32fs: the BCK frequency at 32 times the sample frequency
+/// - bck_64fs: the BCK frequency at 64 times the sample frequency
+#[derive(Clone, Copy)]
+pub struct DatasheetRates {
+    pub lrck: f32,
+    pub bck_32fs: f32,
+    pub bck_64fs: f32,
+}
+
+/// The datasheet rates of every `SampleFrequency` preset, indexed by `SampleFrequency::index`.
+/// All frequencies are pulled from Table 11. BCK Rates (MHz) by LRCK Sample Rate for PCM510xA PLL Operation
+/// From the "PCM510xA 2.1 VRMS, 112/106/100 dB Audio Stereo DAC with PLL and 32-bit, 384 kHz PCM Interface" data sheet
+pub const DATASHEET_RATES: [DatasheetRates; SampleFrequency::COUNT] = [
+    DatasheetRates { lrck: 32_000f32, bck_32fs: 1.024E06_f32, bck_64fs: 2.048E06_f32 },
+    DatasheetRates { lrck: 44_100f32, bck_32fs: 1.4112E06_f32, bck_64fs: 2.8224E06_f32 },
+    DatasheetRates { lrck: 48_000f32, bck_32fs: 1.536E06_f32, bck_64fs: 3.072E06_f32 },
+    DatasheetRates { lrck: 96_000f32, bck_32fs: 3.072E06_f32, bck_64fs: 6.144E06_f32 },
+    DatasheetRates { lrck: 192_000f32, bck_32fs: 6.144E06_f32, bck_64fs: 12.288E06_f32 },
+    DatasheetRates { lrck: 384_000f32, bck_32fs: 12.288E06_f32, bck_64fs: 24.576E06_f32 },
+];
+
+impl SampleFrequency {
+    /// The number of presets, and the length of `DATASHEET_RATES`.
+    pub const COUNT: usize = 6;
+
+    /// Every preset, in ascending order of frequency.
+    pub const ALL: [SampleFrequency; SampleFrequency::COUNT] = [
+        SampleFrequency::Freq32khz,
+        SampleFrequency::Freq44_1khz,
+        SampleFrequency::Freq48khz,
+        SampleFrequency::Freq96khz,
+        SampleFrequency::Freq192khz,
+        SampleFrequency::Freq384khz,
+    ];
+
+    /// # Purpose
+    /// The position of this preset in `ALL` and `DATASHEET_RATES`. The match is exhaustive, so adding
+    /// a variant will not compile until it is given a row, and `COUNT` sizes both arrays, so a
+    /// missing or extra row will not compile either.
+    pub const fn index(&self) -> usize {
+        match self {
+            SampleFrequency::Freq32khz => 0,
+            SampleFrequency::Freq44_1khz => 1,
+            SampleFrequency::Freq48khz => 2,
+            SampleFrequency::Freq96khz => 3,
+            SampleFrequency::Freq192khz => 4,
+            SampleFrequency::Freq384khz => 5,
+        }
+    }
+
+    /// # Purpose
+    /// The preset for a sample rate of `hz`, or `None` if there is no preset at exactly that rate.
+    pub fn from_hz(hz: u32) -> Option<SampleFrequency> {
+        SampleFrequency::ALL.into_iter().find(|preset| preset.as_hz() == hz)
+    }
+
+    /// # Purpose
+    /// The LRCK (sample) frequency of this preset in Hertz.
+    pub fn as_hz(&self) -> u32 {
+        self.datasheet_rates().lrck as u32
+    }
+
+    /// # Purpose
+    /// The datasheet row for this preset.
+    pub fn datasheet_rates(&self) -> DatasheetRates {
+        DATASHEET_RATES[self.index()]
+    }
+
+    /// # Purpose
+    /// The (lrck, bck) frequency pair in Hertz for this preset, with the BCK at 64fs.
+    /// The PCM5100A will accept 32 or 64 times the sampling rate and the ratio actually used is
+    /// picked by `BckRatio::resolve`, see `bck_hz` for the rate at a given ratio.
+    pub fn rates(&self) -> (f32, f32) {
+        let rates = self.datasheet_rates();
+        (rates.lrck, rates.bck_64fs)
+    }
+
+    /// # Purpose
+    /// The LRCK (sample) frequency of this preset in Hertz, as the float the divisor math takes.
+    pub fn lrck_hz(&self) -> f32 {
+        self.datasheet_rates().lrck
+    }
+
+    /// # Purpose
+    /// The BCK frequency for this preset at `ratio` as Table 11 lists it, e.g. 1.536MHz for 48khz
+    /// at 32fs. Every BCK divisor is worked out from this, so the table is what the clocks are
+    /// set from. `Auto` is treated as 64fs, resolve it first to get the rate the hardware will use.
+    pub fn bck_hz(&self, ratio: BckRatio) -> f32 {
+        let rates = self.datasheet_rates();
+        match ratio {
+            BckRatio::Ratio32 => rates.bck_32fs,
+            BckRatio::Ratio64 | BckRatio::Auto => rates.bck_64fs,
+        }
+    }
+}
+
+impl TryFrom<u32> for SampleFrequency {
+    type Error = I2sError;
+
+    /// # Purpose
+    /// The preset for a sample rate of `hz`, e.g. one received from a USB host or a command
+    /// interface. Fails with `I2sError::UnsupportedRate` if no preset runs at exactly that rate.
+    fn try_from(hz: u32) -> Result<Self, Self::Error> {
+        SampleFrequency::from_hz(hz).ok_or(I2sError::UnsupportedRate { hz })
+    }
+}
+
+/// # Purpose
+/// The number of BCK periods in one LRCK period (one stereo frame). The PCM510xA accepts
+/// a BCK of either 32 or 64 times the sampling rate.
+/// # Members
+/// - Ratio32:  32fs, 16 BCK periods per channel
+/// - Ratio64:  64fs, 32 BCK periods per channel
+/// - Auto:     64fs, unless that needs too small a divisor for the sample rate, see `resolve`
+#[derive(Clone, Copy, PartialEq)]
+pub enum BckRatio {
+    Ratio32,
+    Ratio64,
+    Auto,
+}
+
+impl BckRatio {
+    /// # Purpose
+    /// The BCK multiple of the LRCK frequency, or `None` for `Auto` which has not been resolved yet.
+    pub fn multiplier(&self) -> Option<f32> {
+        match self {
+            BckRatio::Ratio32 => Some(32.0),
+            BckRatio::Ratio64 => Some(64.0),
+            BckRatio::Auto => None,
+        }
+    }
+
+    /// # Purpose
+    /// The width of one channel's slot in BCK periods, i.e. how many bits of each FIFO word are
+    /// shifted out before the next word is pulled. At 32fs each word carries one 16 bit channel
+    /// sample. `Auto` is treated as 64fs.
+    pub fn slot_bits(&self) -> u8 {
+        match self {
+            BckRatio::Ratio32 => 16,
+            BckRatio::Ratio64 | BckRatio::Auto => 32,
+        }
+    }
+
+    /// # Purpose
+    /// The ratio whose slots are as wide as a `depth` bit sample needs, so the program's bit loop
+    /// clocks exactly that many bits per channel before pulling the next word: 32fs for `Bits16`
+    /// and 64fs for `Bits24` and `Bits32`, which the PCM510xA reads as 16 and 24 or 32 bit words.
+    /// Unlike `Auto`, which picks 32fs at 384k, this never cuts a 24 bit sample down to 16 bits.
+    pub fn for_bit_depth(depth: BitDepth) -> BckRatio {
+        match depth {
+            BitDepth::Bits16 => BckRatio::Ratio32,
+            BitDepth::Bits24 | BitDepth::Bits32 => BckRatio::Ratio64,
+        }
+    }
+
+    /// # Purpose
+    /// Resolves `Auto` into a concrete ratio for the given LRCK frequency and PIO clock, and
+    /// returns concrete ratios unchanged. The returned ratio is the one the hardware is set up with.
+    ///
+    /// # Heuristic
+    /// 64fs is used whenever its BCK divisor is at least `MIN_COMFORTABLE_DIVISOR`, as it carries
+    /// the full 24 bits and is what every preset in Table 11 of the PCM510xA datasheet is listed
+    /// at. Only below that, where 64fs is marginal, does it drop to 32fs, which halves BCK and
+    /// doubles the divisor but sends 16 bit words. A better quantization error is never worth
+    /// the bit depth, so the ppm figures play no part.
+    ///
+    /// At the stock 125MHz system clock 32k to 192k stay at 64fs, and only 384k switches to 32fs,
+    /// with a divisor of about 5.09 instead of 2.54. Ask for `Ratio64` explicitly to keep 24 bits
+    /// at 384k.
+    pub fn resolve(self, lrck_freq: f32, sys_clk: f32) -> BckRatio {
+        if self != BckRatio::Auto {
+            return self;
+        }
+        if bck_divisor(sys_clk, lrck_freq, BckRatio::Ratio64) < MIN_COMFORTABLE_DIVISOR {
+            BckRatio::Ratio32
+        } else {
+            BckRatio::Ratio64
+        }
+    }
+}
+
+/// # Purpose
+/// The clock divisor the data/BCK state machine needs to produce `lrck_freq * ratio` on BCK.
+/// `ratio` must already be resolved, `Auto` is treated as 64fs.
+pub fn bck_divisor(sys_clk: f32, lrck_freq: f32, ratio: BckRatio) -> f32 {
+    let bck_freq = lrck_freq * ratio.multiplier().unwrap_or(64.0);
+    (sys_clk / DATA_CYCLES_PER_BCK) / bck_freq
+}
+
+/// # Purpose
+/// The whole and fractional (in 256ths) parts of the clock divisor that runs a program taking
+/// `cycles_per` instructions per period at `target` periods per second from a `base` Hertz clock.
+/// Fails if the divisor is not a finite number the PIO divider can hold, i.e. if its whole part
+/// is outside 1..=65535, so a rate that can not be reached is never silently wrapped, saturated
+/// or divided by zero into some other rate.
+pub fn compute_divisor(base: f32, cycles_per: f32, target: f32) -> Result<(u16, u8), I2sError> {
+    split_divisor(base / cycles_per / target)
+}
+
+/// # Purpose
+/// Splits `divisor` into the whole and fractional (in 256ths) parts the PIO clock divider takes,
+/// failing with `I2sError::DivisorOutOfRange` unless it is finite and its whole part fits 1..=65535.
+fn split_divisor(divisor: f32) -> Result<(u16, u8), I2sError> {
+    if !divisor.is_finite() || !(1.0..65536.0).contains(&divisor) {
+        return Err(I2sError::DivisorOutOfRange { divisor });
+    }
+
+    let whole = divisor as u16;
+    let frac = ((divisor - whole as f32) * 256.0) as u8;
+    Ok((whole, frac))
+}
+
+/// # Purpose
+/// The whole and fractional clock divisor that produces an MCLK of `multiple` times `lrck_hz`
+/// from `sys_clk`, e.g. 256 for the common 256fs. Fails with `I2sError::DivisorOutOfRange` if
+/// that MCLK can not be reached, which at 125MHz is anything over 62.5MHz (512fs at 192khz) and
+/// any `multiple` of 0.
+pub fn mclk_divisor(sys_clk: f32, lrck_hz: f32, multiple: u16) -> Result<(u16, u8), I2sError> {
+    compute_divisor(sys_clk, MCLK_CYCLES_PER_PERIOD, lrck_hz * multiple as f32)
+}
+
+/// # Purpose
+/// The signed error in parts per million of the divisor `whole` + `frac`/256 the PIO runs at
+/// against the ideal `target_div`. Positive means the quantized divisor is larger, so the clock it
+/// produces, and the pitch, is low by about the same amount.
+///
+/// E.g. the 192khz BCK at 64fs from 125MHz wants 125MHz / 2 / 12.288MHz = 5.08626, which is
+/// quantized to 5 + 22/256 = 5.08594 for an error of -64ppm.
+pub fn divisor_error_ppm(target_div: f32, whole: u16, frac: u8) -> f32 {
+    let quantized = whole as f32 + frac as f32 / 256.0;
+    (quantized - target_div) / target_div * 1E06
+}
+
+/// # Purpose
+/// The divisor the PIO actually runs at once `divisor` is split into whole + frac/256. Fails with
+/// `I2sError::DivisorOutOfRange` for a divisor the divider can not hold.
+pub fn quantize_divisor(divisor: f32) -> Result<f32, I2sError> {
+    let (whole, frac) = split_divisor(divisor)?;
+    Ok(whole as f32 + frac as f32 / 256.0)
+}
+
+/// # Purpose
+/// The frequencies the hardware actually produces for one preset, and how far off they are.
+/// # Members
+/// - sample_frequency: the preset these numbers are for
+/// - bck_ratio:        the resolved BCK ratio used
+/// - lrck_hz:          achieved LRCK frequency
+/// - bck_hz:           achieved BCK frequency
+/// - lrck_ppm:         signed LRCK error relative to the preset, in parts per million
+/// - bck_ppm:          signed BCK error relative to the datasheet BCK, in parts per million
+#[derive(Clone, Copy)]
+pub struct AchievedRates {
+    pub sample_frequency: SampleFrequency,
+    pub bck_ratio: BckRatio,
+    pub lrck_hz: f32,
+    pub bck_hz: f32,
+    pub lrck_ppm: f32,
+    pub bck_ppm: f32,
+}
+
+/// # Purpose
+/// Works out the LRCK and BCK frequencies the state machine really runs at for `sample_frequency`
+/// once its divisor is quantized, given the PIO clock `sys_clk`. Fails with
+/// `I2sError::DivisorOutOfRange` if `sys_clk` can not produce its BCK at all.
+pub fn achieved_rates(
+    sys_clk: f32,
+    sample_frequency: SampleFrequency,
+    bck_ratio: BckRatio,
+) -> Result<AchievedRates, I2sError> {
+    let lrck_freq = sample_frequency.lrck_hz();
+    let bck_ratio = bck_ratio.resolve(lrck_freq, sys_clk);
+    let bck_freq = sample_frequency.bck_hz(bck_ratio);
+
+    // LRCK comes out of the same state machine as BCK, one period per frame of 2 slots
+    let bck_div = quantize_divisor(bck_divisor(sys_clk, lrck_freq, bck_ratio))?;
+    let bck_hz = sys_clk / (DATA_CYCLES_PER_BCK * bck_div);
+    let lrck_hz = bck_hz / (2.0 * bck_ratio.slot_bits() as f32);
+
+    Ok(AchievedRates {
+        sample_frequency,
+        bck_ratio,
+        lrck_hz,
+        bck_hz,
+        lrck_ppm: (lrck_hz - lrck_freq) / lrck_freq * 1E06,
+        bck_ppm: (bck_hz - bck_freq) / bck_freq * 1E06,
+    })
+}
+
+/// # Purpose
+/// A datasheet style accuracy summary: the achieved rates and ppm errors of every
+/// `SampleFrequency` preset at the PIO clock `sys_clk`, in the order of `SampleFrequency::ALL`.
+/// Meant to be printed once at boot to document a board's audio clock accuracy. Each preset gets
+/// its own result, so one rate `sys_clk` can not reach does not hide the others.
+pub fn frequency_accuracy_report(
+    sys_clk: f32,
+    bck_ratio: BckRatio,
+) -> [Result<AchievedRates, I2sError>; SampleFrequency::COUNT] {
+    SampleFrequency::ALL.map(|sample_frequency| achieved_rates(sys_clk, sample_frequency, bck_ratio))
+}
+
+/// # Purpose
+/// Checks the BCK divisor of every `SampleFrequency` preset at both 32fs and 64fs fits the PIO
+/// divider at the PIO clock `sys_clk`, failing with the `I2sError::DivisorOutOfRange` of the first
+/// that does not. Worth calling once after changing the system clock, as all of them then still
+/// work whatever rate and ratio is picked later.
+///
+/// The tightest corners are 384khz at 64fs, wanting a divisor of at least 1 and so a `sys_clk` of
+/// at least 49.152MHz, and 32khz at 32fs, whose divisor stays under 65536 up to a `sys_clk` of
+/// over 134GHz. At the stock 125MHz the divisors run from about 2.54 to 61.
+pub fn check_all_divisors(sys_clk: f32) -> Result<(), I2sError> {
+    for sample_frequency in SampleFrequency::ALL {
+        for ratio in [BckRatio::Ratio32, BckRatio::Ratio64] {
+            compute_divisor(sys_clk, DATA_CYCLES_PER_BCK, sample_frequency.bck_hz(ratio))?;
+        }
+    }
+    Ok(())
+}
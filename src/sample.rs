@@ -0,0 +1,226 @@
+//! Sample formats and the packing of samples into the words the tx fifo expects.
+
+pub const BITSHIFT_ONE_BYTE: u8 = 8;
+// Largest positive 24 bit sample, i.e. 0dBFS
+pub const FULL_SCALE_24BIT: i32 = 0x7F_FFFF;
+// Channels in every frame, left and right
+pub const CHANNELS: usize = 2;
+
+/// # Purpose
+/// The width of one sample as the DAC sees it.
+/// # Members
+/// - Bits16:   16 bit samples
+/// - Bits24:   24 bit samples, the format the generators produce
+/// - Bits32:   32 bit samples
+#[derive(Clone, Copy, PartialEq)]
+pub enum BitDepth {
+    Bits16,
+    Bits24,
+    Bits32,
+}
+
+impl BitDepth {
+    /// # Purpose
+    /// Returns the largest positive sample value at this depth.
+    pub fn full_scale(self) -> i32 {
+        match self {
+            BitDepth::Bits16 => i16::MAX as i32,
+            BitDepth::Bits24 => FULL_SCALE_24BIT,
+            BitDepth::Bits32 => i32::MAX,
+        }
+    }
+
+    /// # Purpose
+    /// Returns the number of bits in one sample.
+    pub const fn bits(self) -> usize {
+        match self {
+            BitDepth::Bits16 => 16,
+            BitDepth::Bits24 => 24,
+            BitDepth::Bits32 => 32,
+        }
+    }
+}
+
+/// # Purpose
+/// Converts a signed sample of `depth` bits into the left justified u32 word the PCM510xA expects
+/// in a 32 bit I2S slot. The sample's sign bit lands on bit 31 and the unused low bits are zero,
+/// so a 16 bit -1 becomes 0xFFFF_0000 and the 24 bit minimum -0x80_0000 becomes 0x8000_0000.
+/// It works on the value rather than on the bytes of `num` in memory, so it needs no unsafe
+/// pointer reads and gives the same word whatever the byte order of the target.
+///
+/// `num` must already be in range for `depth` (e.g. -0x80_0000..=0x7F_FFFF for `Bits24`), any
+/// bits above the depth are shifted out and lost.
+///
+/// 16 bit PCM can be passed straight in as `sample as i32` with `Bits16`, e.g. -0x1234 becomes
+/// 0xEDCC_0000. That is the same word `i16_to_sample` and the `Bits24` cast give, so 16 bit source
+/// material never needs upconverting by hand.
+pub const fn cast_to_u32_as_i32(num: i32, depth: BitDepth) -> u32 {
+    (num as u32) << (32 - depth.bits())
+}
+
+/// # Purpose
+/// The inverse of `cast_to_u32_as_i32`, recovers a `depth` bit sample from a left justified word,
+/// such as one recorded by `I2sInput`. The arithmetic shift keeps the sign, and any bits below the
+/// depth are shifted out, so a 32 bit recorded word read with `Bits24` loses its bottom byte.
+pub const fn cast_from_u32_as_i32(word: u32, depth: BitDepth) -> i32 {
+    (word as i32) >> (32 - depth.bits())
+}
+
+/// # Purpose
+/// A function to bitreverse a number for sending little endian to a big endian style machine.
+/// Mirrors all 32 bit positions, bit 0 trades places with bit 31 and so on, so applying it twice
+/// gives back the original number.
+pub const fn bit_reverse(num: u32) -> u32 {
+    num.reverse_bits()
+}
+
+/// # Purpose
+/// Packs a 24 bit sample into the word format the tx fifo expects, left justified and bit
+/// reversed so the state machine's right shifts send the MSB first.
+pub const fn pack_sample(sample: i32) -> u32 {
+    bit_reverse(cast_to_u32_as_i32(sample, BitDepth::Bits24))
+}
+
+/// # Purpose
+/// Widens a 16 bit PCM sample to the 24 bit sample range by sign extending it and left justifying
+/// it into the top 16 of the 24 bits. `i16::MIN` becomes the 24 bit minimum -0x80_0000 and
+/// `i16::MAX` becomes 0x7F_FF00, the low byte is always zero.
+pub fn i16_to_sample(sample: i16) -> i32 {
+    (sample as i32) << BITSHIFT_ONE_BYTE
+}
+
+/// # Purpose
+/// Decodes one little-endian PCM sample, as stored in a WAV `data` chunk, to the 24 bit sample
+/// range. `bytes` holds exactly one sample: 2 bytes for 16 bit, 3 for 24 bit or 4 for 32 bit (of
+/// which the top 24 bits are kept). Returns `None` for any other length.
+///
+/// WAV stores samples least significant byte first, while the DAC wants them MSB first on the wire.
+/// Decoding to a plain `i32` here and leaving the wire format to `pack_sample` means the two byte
+/// orders are only ever dealt with in one place each, so WAV data never gets reversed twice.
+pub fn le_pcm_to_sample(bytes: &[u8]) -> Option<i32> {
+    match *bytes {
+        [b0, b1] => Some(i16_to_sample(i16::from_le_bytes([b0, b1]))),
+        // put the 3 bytes at the top of an i32 and shift back down to sign extend
+        [b0, b1, b2] => Some(i32::from_le_bytes([0, b0, b1, b2]) >> BITSHIFT_ONE_BYTE),
+        [b0, b1, b2, b3] => Some(i32::from_le_bytes([b0, b1, b2, b3]) >> BITSHIFT_ONE_BYTE),
+        _ => None,
+    }
+}
+
+/// # Purpose
+/// Converts one little-endian WAV sample straight to the word the tx fifo expects, see
+/// `le_pcm_to_sample`.
+pub fn le_pcm_to_wire(bytes: &[u8]) -> Option<u32> {
+    le_pcm_to_sample(bytes).map(pack_sample)
+}
+
+/// # Purpose
+/// The inverse of `pack_sample`, recovers the sample from a word meant for the tx fifo.
+pub fn unpack_sample(word: u32) -> i32 {
+    // an arithmetic shift undoes the left justification and keeps the sign
+    (bit_reverse(word) as i32) >> (32 - BitDepth::Bits24.bits())
+}
+
+/// # Purpose
+/// The order the bits of a tx fifo word are shifted out in, and so how samples are packed into
+/// words for it. Either way the DAC gets the MSB first, as I2S wants.
+/// # Members
+/// - Reversed: words are bit reversed in software by `pack_sample` and the OSR shifts right
+/// - MsbFirst: words are left justified as they are and the OSR shifts left, with no reversal
+///
+/// # Which to pick
+/// `Reversed` is the default and the format every packed table and helper in this crate produces,
+/// `SINE_TABLE`, `le_pcm_to_wire` and the `WavetableSource` crossfade included. `MsbFirst` saves
+/// the reversal on every sample of the sample paths (`write_stereo` and the paths built on it),
+/// which is a software loop on the Cortex-M0+ as it has no bit reverse instruction. Packed words
+/// handed to the output as they are, through `write_sample`, `write_channels` or DMA, then have to
+/// be packed with `BitOrder::pack` (or converted with `from_reversed`) to match.
+///
+/// A sample of `0x40_0001` packs to 0x0080_0002 for `Reversed`, shifted out from bit 0 up, and to
+/// 0x4000_0100 for `MsbFirst`, shifted out from bit 31 down. Both put a 0, a 1, 21 zeros and a
+/// final 1 on the wire, the 24 sample bits from the MSB, followed by the 8 zero padding bits.
+#[derive(Clone, Copy, PartialEq)]
+pub enum BitOrder {
+    Reversed,
+    MsbFirst,
+}
+
+impl BitOrder {
+    /// # Purpose
+    /// Packs a 24 bit sample into a tx fifo word for this order.
+    pub fn pack(self, sample: i32) -> u32 {
+        match self {
+            BitOrder::Reversed => pack_sample(sample),
+            BitOrder::MsbFirst => cast_to_u32_as_i32(sample, BitDepth::Bits24),
+        }
+    }
+
+    /// # Purpose
+    /// The inverse of `pack`, recovers the sample from a tx fifo word of this order.
+    pub fn unpack(self, word: u32) -> i32 {
+        match self {
+            BitOrder::Reversed => unpack_sample(word),
+            BitOrder::MsbFirst => (word as i32) >> (32 - BitDepth::Bits24.bits()),
+        }
+    }
+
+    /// # Purpose
+    /// Converts a word packed by `pack_sample`, i.e. for `Reversed`, to this order.
+    pub fn from_reversed(self, word: u32) -> u32 {
+        match self {
+            BitOrder::Reversed => word,
+            BitOrder::MsbFirst => bit_reverse(word),
+        }
+    }
+
+    /// # Purpose
+    /// Moves a word of this order to the end of a 32 bit slot, see `to_right_justified`.
+    pub fn right_justify(self, word: u32) -> u32 {
+        match self {
+            BitOrder::Reversed => to_right_justified(word),
+            BitOrder::MsbFirst => self.unpack(word) as u32,
+        }
+    }
+}
+
+/// # Purpose
+/// Converts a packed word from the left justified form `pack_sample` gives to the right justified
+/// form of a 32 bit slot: the 24 bit sample sign extended to 32 bits, so it ends on the last bit
+/// of the slot and the 8 bits before it repeat the sign.
+pub fn to_right_justified(word: u32) -> u32 {
+    bit_reverse(unpack_sample(word) as u32)
+}
+
+/// # Purpose
+/// The mean of packed fifo words, in codes of `depth` (so 1.0 is one LSB at that depth).
+///
+/// A clean test tone covering whole periods should average to almost exactly zero, anything else
+/// is a DC offset from an asymmetric or broken generator. Returns 0.0 for an empty buffer.
+pub fn mean_code(words: &[u32], depth: BitDepth) -> f32 {
+    if words.is_empty() {
+        return 0.0;
+    }
+
+    let sum: i64 = words.iter().map(|word| unpack_sample(*word) as i64).sum();
+    let mean = sum as f32 / words.len() as f32;
+    match depth {
+        BitDepth::Bits16 => mean / 256.0,
+        BitDepth::Bits24 => mean,
+        BitDepth::Bits32 => mean * 256.0,
+    }
+}
+
+/// # Purpose
+/// Logs the first `n` words of `words` as hex over defmt, one line per word, numbered from 0.
+///
+/// Pass it the buffer that is about to be written to the tx fifo, e.g. the `samples` table after
+/// `generate_sine_wave`. It shows exactly what the driver hands the PIO after packing and bit
+/// reversal, which makes format and endianness mistakes visible without a logic analyzer. It is a
+/// capture of the generator output, not of the pins, so anything the PIO itself gets wrong (shift
+/// direction, framing against LRCK) will not show up here.
+#[cfg(feature = "defmt")]
+pub fn dump_wire_words(words: &[u32], n: usize) {
+    for (i, word) in words.iter().take(n).enumerate() {
+        defmt::info!("wire[{}] = {=u32:#010x}", i, *word);
+    }
+}
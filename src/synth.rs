@@ -0,0 +1,721 @@
+//! Test signal generators and oscillators.
+
+use crate::sample::{pack_sample, FULL_SCALE_24BIT};
+
+pub const TABLE_SIZE: usize = 1920;
+pub const AMPLITUDE: i32 = 0x6FFFFF;
+pub const FREQUENCY: f32 = 300.0;
+pub const SAMPLE_RATE: f32 = 192_000.0;
+pub const PI: f32 = core::f32::consts::PI;
+// How long each DTMF digit sounds and the silence between digits, in milliseconds
+pub const DTMF_TONE_MS: u32 = 100;
+pub const DTMF_GAP_MS: u32 = 100;
+// Samples between the exact frequency computations of a `Chirp`, it slides linearly in between
+pub const CHIRP_BLOCK: u32 = 256;
+
+/// The `FREQUENCY` sine at `SAMPLE_RATE` and `AMPLITUDE`, packed for the tx fifo. Built at compile
+/// time, so it costs nothing at boot and sits in flash where DMA can read it directly.
+pub static SINE_TABLE: [u32; TABLE_SIZE] = sine_table();
+/// `SINE_TABLE` interleaved with silence as (left, right) frames, the hard panned tone of
+/// `generate_stereo` ready for `I2sOutput::start_dma`.
+pub static SINE_FRAMES: [u32; 2 * TABLE_SIZE] = sine_frames();
+
+/// # Purpose
+/// Approximates sin(`angle`) for any angle in radians, see `sine_turns`.
+pub fn sine(angle: f32) -> f32 {
+    sine_turns(angle / (2.0 * PI))
+}
+
+/// # Purpose
+/// Approximates sin(2π * `turns`), i.e. the sine of an angle given in whole turns.
+///
+/// The whole turns are dropped first, then the symmetry of the sine folds the angle into
+/// [-π/2, π/2], where a Taylor series up to x^11 is accurate to about 6e-8, below the f32
+/// resolution of the result. Reducing in turns rather than radians keeps the reduction exact for
+/// phases that are already fractions of a period, which is what keeps generated tables within a
+/// couple of LSB of an ideal 24 bit sine. No `libm` is needed.
+pub const fn sine_turns(turns: f32) -> f32 {
+    let mut turns = turns - (turns as i32) as f32;
+    if turns > 0.5 {
+        turns -= 1.0;
+    } else if turns < -0.5 {
+        turns += 1.0;
+    }
+    // sin(π - x) = sin(x) folds the outer quarters onto the inner ones
+    if turns > 0.25 {
+        turns = 0.5 - turns;
+    } else if turns < -0.25 {
+        turns = -0.5 - turns;
+    }
+
+    let x = 2.0 * PI * turns;
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0 * (1.0 - x2 / 110.0)))))
+}
+
+/// # Purpose
+/// Generates an array of u32 samples that represent an i32 value at the byte level
+///
+/// The tone is a sine of `freq_hz` at `sample_rate` with a peak of `amplitude`, which is clamped
+/// to the 24 bit range of +-`FULL_SCALE_24BIT`. Nothing is band limited, so a `freq_hz` above
+/// Nyquist (`sample_rate` / 2) aliases and plays as `sample_rate` - `freq_hz` instead.
+/// `FREQUENCY`, `AMPLITUDE` and `SAMPLE_RATE` give the default tone, which `copy_sine_table`
+/// copies without computing it.
+///
+/// At most `TABLE_SIZE` entries are filled, a shorter buffer gets a shorter table and an empty
+/// buffer is left alone.
+pub fn generate_sine_wave(samples: &mut [u32], freq_hz: f32, amplitude: i32, sample_rate: f32) {
+    fill_waveform(samples, Waveform::Sine, freq_hz, sample_rate, amplitude);
+}
+
+/// # Purpose
+/// Like `generate_sine_wave`, but first snaps `requested_hz` to the nearest frequency that fits a
+/// whole number of periods in the table, `loop_frequency`, so the last entry runs on into the
+/// first with no seam click when the table is looped. Returns the frequency actually generated.
+///
+/// The phase of each entry is worked out from whole numbers, entry * periods modulo the table
+/// length, so the periods line up exactly at the seam rather than to within float rounding.
+pub fn generate_sine_wave_looped(samples: &mut [u32], requested_hz: f32, sample_rate: f32, amplitude: i32) -> f32 {
+    let len = samples.len().min(TABLE_SIZE);
+    let periods = whole_periods(requested_hz, sample_rate, len);
+    let amplitude = amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT);
+    for (i, out) in samples[..len].iter_mut().enumerate() {
+        let phase = (i as u64 * periods as u64 % len as u64) as f32 / len as f32;
+        *out = pack_sample((amplitude as f32 * sine_turns(phase)) as i32);
+    }
+    loop_frequency(requested_hz, sample_rate, samples.len())
+}
+
+/// # Purpose
+/// Returns the frequency nearest `requested_hz` that repeats a whole number of times in a table
+/// of `len` samples at `sample_rate`, i.e. a multiple of `sample_rate` / `len`, with `len` capped
+/// at `TABLE_SIZE` as the generators cap it. Passing that to `generate_square_wave` or the other
+/// table generators makes their tables loop cleanly too. Requests below half a period per table
+/// snap to 0Hz, and an empty table leaves `requested_hz` as it is.
+pub fn loop_frequency(requested_hz: f32, sample_rate: f32, len: usize) -> f32 {
+    let len = len.min(TABLE_SIZE);
+    if len == 0 {
+        return requested_hz;
+    }
+    whole_periods(requested_hz, sample_rate, len) as f32 * sample_rate / len as f32
+}
+
+/// # Purpose
+/// The whole number of periods of `requested_hz` nearest to fitting in `len` samples.
+fn whole_periods(requested_hz: f32, sample_rate: f32, len: usize) -> u32 {
+    (requested_hz * len as f32 / sample_rate + 0.5) as u32
+}
+
+/// # Purpose
+/// Fills up to `TABLE_SIZE` entries of `samples` with the default tone from `SINE_TABLE`, the same
+/// words `generate_sine_wave` gives for `FREQUENCY`, `AMPLITUDE` and `SAMPLE_RATE`.
+pub fn copy_sine_table(samples: &mut [u32]) {
+    let len = samples.len().min(TABLE_SIZE);
+    samples[..len].copy_from_slice(&SINE_TABLE[..len]);
+}
+
+/// # Purpose
+/// Fills `samples` with the default `FREQUENCY` tone at `SAMPLE_RATE` and `AMPLITUDE` in the shape
+/// of `waveform`, i.e. `copy_sine_table` for any of the shapes. The parameterized generators
+/// below take the same `samples` first and add the frequency, rate and amplitude.
+///
+/// At most `TABLE_SIZE` entries are filled, which holds a whole 3 periods of the default tone, so
+/// the table loops without a jump for every shape. The square wave is the one to check the wire
+/// format with: every word is either `pack_sample(AMPLITUDE)` or `pack_sample(-AMPLITUDE)`.
+pub fn generate_waveform(samples: &mut [u32], waveform: Waveform) {
+    match waveform {
+        Waveform::Sine => copy_sine_table(samples),
+        _ => fill_waveform(samples, waveform, FREQUENCY, SAMPLE_RATE, AMPLITUDE),
+    }
+}
+
+/// # Purpose
+/// Fills `samples` with a square wave of `frequency` Hz at `sample_rate`, alternating between
+/// +`amplitude` and -`amplitude` with no band limiting, packed like `generate_sine_wave`.
+///
+/// At most `TABLE_SIZE` entries are filled. The table loops without a phase jump when it holds a
+/// whole number of periods, e.g. 3 periods of 300Hz in `TABLE_SIZE` at 192khz, and then exactly
+/// half the words are at +`amplitude` and half at -`amplitude` if each period is an even number
+/// of samples.
+pub fn generate_square_wave(samples: &mut [u32], frequency: f32, sample_rate: f32, amplitude: i32) {
+    fill_waveform(samples, Waveform::Square, frequency, sample_rate, amplitude);
+}
+
+/// # Purpose
+/// Fills `samples` with a triangle wave of `frequency` Hz at `sample_rate`, packed like
+/// `generate_sine_wave`. Each period rises linearly from -`amplitude` to +`amplitude` over its
+/// first half and falls back over the second, so the peaks are the only corners.
+///
+/// At most `TABLE_SIZE` entries are filled, see `generate_square_wave` for when the table loops
+/// cleanly.
+pub fn generate_triangle_wave(samples: &mut [u32], frequency: f32, sample_rate: f32, amplitude: i32) {
+    fill_waveform(samples, Waveform::Triangle, frequency, sample_rate, amplitude);
+}
+
+/// # Purpose
+/// Fills `samples` with a sawtooth wave of `frequency` Hz at `sample_rate`, packed like
+/// `generate_sine_wave`. Each period ramps linearly from -`amplitude` up towards +`amplitude` and
+/// then jumps back, with no band limiting of the jump.
+///
+/// At most `TABLE_SIZE` entries are filled, see `generate_square_wave` for when the table loops
+/// cleanly.
+pub fn generate_sawtooth_wave(samples: &mut [u32], frequency: f32, sample_rate: f32, amplitude: i32) {
+    fill_waveform(samples, Waveform::Sawtooth, frequency, sample_rate, amplitude);
+}
+
+/// # Purpose
+/// Fills `samples` with the sum of a sine per entry of `freqs` at `sample_rate`, e.g. a chord or
+/// a multi-tone test signal, packed like `generate_sine_wave`. `amplitudes` gives each tone's peak
+/// as a fraction of `AMPLITUDE`. If the fractions add up to more than 1.0 they are all scaled down
+/// by the same factor, so however the tones line up the sum never passes `AMPLITUDE`.
+///
+/// `freqs` and `amplitudes` must be the same length, which is checked in debug builds, a release
+/// build ignores the extra entries of the longer one. No tones give silence. At most `TABLE_SIZE`
+/// entries are filled, and each tone uses the same exact phase wrapping as `generate_sine_wave`.
+pub fn generate_multitone(samples: &mut [u32], freqs: &[f32], amplitudes: &[f32], sample_rate: f32) {
+    debug_assert_eq!(freqs.len(), amplitudes.len(), "one amplitude per frequency");
+    let total: f32 = amplitudes
+        .iter()
+        .map(|amplitude| if *amplitude < 0.0 { -amplitude } else { *amplitude })
+        .sum();
+    let scale = if total > 1.0 { AMPLITUDE as f32 / total } else { AMPLITUDE as f32 };
+
+    for (i, out) in samples.iter_mut().enumerate().take(TABLE_SIZE) {
+        let mut sum = 0.0;
+        for (frequency, amplitude) in freqs.iter().zip(amplitudes) {
+            let phase = (i as f32 * frequency) % sample_rate / sample_rate;
+            sum += amplitude * sine_turns(phase);
+        }
+        *out = pack_sample((scale * sum) as i32);
+    }
+}
+
+/// # Purpose
+/// Fills `samples` with the sum of one sine per `(frequency, amplitude)` in `components` at
+/// `sample_rate`, e.g. a chord or a tone with its harmonics, packed like `generate_sine_wave`.
+/// Each component is a `SineOscillator` of its own, so they keep independent phase accumulators,
+/// and each `amplitude` is a peak in the 24 bit sample range.
+///
+/// # Headroom
+/// Unlike `generate_multitone` nothing is scaled: the sum saturates at +-`FULL_SCALE_24BIT`
+/// rather than wrapping, but saturating is still clipping. The peaks of the components line up
+/// sooner or later, so the sum only stays clean if the amplitudes add up to at most full scale,
+/// e.g. each of 4 components at no more than a quarter of it, 12dB down. At most `TABLE_SIZE`
+/// entries are filled, see `loop_frequency` for frequencies that loop cleanly.
+pub fn generate_additive<const N: usize>(samples: &mut [u32], components: &[(f32, i32); N], sample_rate: f32) {
+    let mut oscillators = components.map(|(freq_hz, amplitude)| SineOscillator::new(freq_hz, sample_rate, amplitude));
+    for out in samples.iter_mut().take(TABLE_SIZE) {
+        let sum: i64 = oscillators.iter_mut().map(|oscillator| oscillator.next_sample() as i64).sum();
+        *out = pack_sample(sum.clamp(-(FULL_SCALE_24BIT as i64), FULL_SCALE_24BIT as i64) as i32);
+    }
+}
+
+/// # Purpose
+/// Fills all of `samples` with a logarithmic sine sweep from `start_hz` to `end_hz` at
+/// `sample_rate`, packed like `generate_sine_wave`, for measuring a frequency response. The
+/// frequency is multiplied by the same ratio every sample, so each octave takes the same time, and
+/// lands on `end_hz` at the last sample. Either direction works, both frequencies have to be
+/// positive or the buffer is left alone. `amplitude` is clamped to the 24 bit range.
+///
+/// The phase is accumulated sample by sample rather than computed from the time, so it is
+/// continuous and the sweep has no clicks. The frequencies are also stretched by the tiny amount
+/// (at most half a period over the whole sweep) that makes the sweep a whole number of periods,
+/// so the buffer loops without a click as well. The sums run in f64 to keep that exact on long
+/// buffers, which is slow on the Cortex-M0+ but only done once per table.
+pub fn generate_chirp(samples: &mut [u32], start_hz: f32, end_hz: f32, sample_rate: f32, amplitude: i32) {
+    if samples.is_empty() || start_hz <= 0.0 || end_hz <= 0.0 {
+        return;
+    }
+    let amplitude = amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT);
+    let steps = (samples.len() - 1).max(1) as f64;
+    let ratio = exp(ln(end_hz as f64 / start_hz as f64) / steps);
+
+    // the periods played over the whole buffer, including the step from the last sample back to
+    // the first, rounded to a whole number for the loop
+    let mut periods = 0.0;
+    let mut step = start_hz as f64 / sample_rate as f64;
+    for _ in 0..samples.len() {
+        periods += step;
+        step *= ratio;
+    }
+    let stretch = ((periods + 0.5) as u64).max(1) as f64 / periods;
+
+    let mut phase = 0.0_f64;
+    let mut step = start_hz as f64 * stretch / sample_rate as f64;
+    for out in samples.iter_mut() {
+        *out = pack_sample((amplitude as f32 * sine_turns(phase as f32)) as i32);
+        phase += step;
+        phase -= (phase as u64) as f64;
+        step *= ratio;
+    }
+}
+
+/// # Purpose
+/// The natural logarithm of `x` > 0 without `libm`, for `generate_chirp` and `Chirp`. Splits `x` into
+/// m * 2^e with m in [1, 2) from its bits, then ln(m) = 2 atanh((m - 1) / (m + 1)) takes a fast
+/// converging series, as (m - 1) / (m + 1) is at most 1/3.
+fn ln(x: f64) -> f64 {
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7FF) as i64 - 1023;
+    let mantissa = f64::from_bits((bits & 0x000F_FFFF_FFFF_FFFF) | (1023 << 52));
+
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let z2 = z * z;
+    let mut term = z;
+    let mut sum = 0.0;
+    for k in 0..20 {
+        sum += term / (2 * k + 1) as f64;
+        term *= z2;
+    }
+    exponent as f64 * core::f64::consts::LN_2 + 2.0 * sum
+}
+
+/// # Purpose
+/// e^`x` without `libm`, for `generate_chirp` and `Chirp`. Halves `x` until it is small, sums the Taylor
+/// series there and squares the result back up.
+fn exp(x: f64) -> f64 {
+    let mut x = x;
+    let mut halvings = 0;
+    while !(-0.5..=0.5).contains(&x) {
+        x *= 0.5;
+        halvings += 1;
+    }
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for k in 1..20 {
+        term *= x / k as f64;
+        sum += term;
+    }
+    for _ in 0..halvings {
+        sum *= sum;
+    }
+    sum
+}
+
+/// # Purpose
+/// Fills up to `TABLE_SIZE` entries of `samples` with packed samples of `waveform`, shared by the
+/// table generators so they all encode the same way. `amplitude` is clamped to the 24 bit range.
+fn fill_waveform(samples: &mut [u32], waveform: Waveform, frequency: f32, sample_rate: f32, amplitude: i32) {
+    let amplitude = amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT);
+    for (i, out) in samples.iter_mut().enumerate().take(TABLE_SIZE) {
+        // the phase in periods, wrapped to [0, 1) before it reaches the waveform. Wrapping before
+        // the division keeps it exact for whole frequencies, i * frequency and the remainder are
+        // whole numbers in an f32
+        let phase = (i as f32 * frequency) % sample_rate / sample_rate;
+        let sample = (amplitude as f32 * waveform.value(phase)) as i32;
+        *out = pack_sample(sample);
+    }
+}
+
+/// # Purpose
+/// Builds `SINE_TABLE`. Computes each entry exactly like `fill_waveform` does for the sine, so the
+/// static and the run time generator give the same words.
+const fn sine_table() -> [u32; TABLE_SIZE] {
+    let mut table = [0; TABLE_SIZE];
+    let mut i = 0;
+    while i < TABLE_SIZE {
+        let phase = (i as f32 * FREQUENCY) % SAMPLE_RATE / SAMPLE_RATE;
+        table[i] = pack_sample((AMPLITUDE as f32 * sine_turns(phase)) as i32);
+        i += 1;
+    }
+    table
+}
+
+/// # Purpose
+/// Builds `SINE_FRAMES` from `SINE_TABLE` on the left and packed silence on the right.
+const fn sine_frames() -> [u32; 2 * TABLE_SIZE] {
+    let mut frames = [pack_sample(0); 2 * TABLE_SIZE];
+    let mut i = 0;
+    while i < TABLE_SIZE {
+        frames[2 * i] = SINE_TABLE[i];
+        i += 1;
+    }
+    frames
+}
+
+/// # Purpose
+/// Fills separate left and right buffers for a hard panned test tone: `left` gets the `FREQUENCY`
+/// sine of `copy_sine_table` and `right` silence. Played with `I2sOutput::write_channels`, a
+/// logic analyzer shows the tone only in the LRCK low half of each frame and all zero data in the
+/// high half, and only the left speaker plays.
+pub fn generate_stereo(left: &mut [u32], right: &mut [u32]) {
+    copy_sine_table(left);
+    right.fill(pack_sample(0));
+}
+
+/// # Purpose
+/// The (row, column) tone pair in Hertz for a DTMF key, or `None` if `digit` is not one of
+/// "0123456789ABCD*#" (A-D may also be lower case).
+pub fn dtmf_tones(digit: char) -> Option<(f32, f32)> {
+    const ROWS: [f32; 4] = [697.0, 770.0, 852.0, 941.0];
+    const COLUMNS: [f32; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+    let (row, column) = match digit {
+        '1' => (0, 0), '2' => (0, 1), '3' => (0, 2), 'A' | 'a' => (0, 3),
+        '4' => (1, 0), '5' => (1, 1), '6' => (1, 2), 'B' | 'b' => (1, 3),
+        '7' => (2, 0), '8' => (2, 1), '9' => (2, 2), 'C' | 'c' => (2, 3),
+        '*' => (3, 0), '0' => (3, 1), '#' => (3, 2), 'D' | 'd' => (3, 3),
+        _ => return None,
+    };
+    Some((ROWS[row], COLUMNS[column]))
+}
+
+/// # Purpose
+/// The shape of a periodic test signal.
+/// # Members
+/// - Sine:     sin(2*pi*phase)
+/// - Square:   +1 for the first half of the period, -1 for the second
+/// - Triangle: -1 rising to +1 over the first half of the period, falling back over the second
+/// - Sawtooth: -1 rising to +1 over the whole period, then jumping back to -1
+#[derive(Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Triangle,
+    Sawtooth,
+}
+
+impl Waveform {
+    /// # Purpose
+    /// The value of the waveform in [-1, 1] at `phase`, where `phase` is the position in the period
+    /// in [0, 1).
+    pub fn value(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Sine => sine_turns(phase),
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => if phase < 0.5 { 4.0 * phase - 1.0 } else { 3.0 - 4.0 * phase },
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+        }
+    }
+}
+
+/// # Purpose
+/// How a `Wavetable` reads between its table entries.
+/// # Members
+/// - Nearest:  take the entry the phase falls in
+/// - Linear:   interpolate linearly between the two neighbouring entries
+/// - Cubic:    Catmull-Rom interpolation over the four surrounding entries
+///
+/// # Distortion and cost
+/// Peak error against an ideal sine, relative to full scale, for a 256 / 1024 entry table of full
+/// scale 24 bit sine samples:
+/// - Nearest:  -32dB / -44dB, one table read per sample
+/// - Linear:   -82dB / -106dB, two reads and one multiply per sample
+/// - Cubic:    -122dB / -128dB, four reads and three 64 bit multiplies per sample
+///
+/// The error of `Nearest` is heard as harmonics and a buzz on most tones. `Linear` is enough for a
+/// 1024 entry table at 16 bit output. `Cubic` is limited by the 24 bit table entries and the 16 bit
+/// interpolation fraction rather than the interpolation itself, and is worth it when tone purity
+/// matters more than CPU.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Linear,
+    Cubic,
+}
+
+/// # Purpose
+/// A DDS oscillator: one period of a waveform in a table of `N` samples, read out by a phase
+/// accumulator. The phase is a `u32` where the full range is one period, so it wraps for free and
+/// never drifts the way an accumulated float would, and any frequency up to Nyquist can be played
+/// from the same table.
+pub struct Wavetable<const N: usize> {
+    table: [i32; N],
+    phase: u32,
+    step: u32,
+    interpolation: Interpolation,
+}
+
+impl<const N: usize> Wavetable<N> {
+    /// # Purpose
+    /// A table holding one period of `waveform` at `amplitude`, silent until a frequency is set.
+    pub fn from_waveform(waveform: Waveform, amplitude: i32) -> Self {
+        let mut table = [0; N];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = (amplitude as f32 * waveform.value(i as f32 / N as f32)) as i32;
+        }
+        Wavetable {
+            table,
+            phase: 0,
+            step: 0,
+            interpolation: Interpolation::Linear,
+        }
+    }
+
+    /// # Purpose
+    /// Sets the playback frequency. Only the phase step changes, so this can be called while
+    /// playing without a jump in the output.
+    pub fn set_frequency(&mut self, freq_hz: f32, sample_rate: f32) {
+        self.step = (freq_hz / sample_rate * 4_294_967_296.0) as u32;
+    }
+
+    /// # Purpose
+    /// Selects how the table is read between entries, see `Interpolation`.
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// # Purpose
+    /// The next output sample, advancing the phase by one step.
+    pub fn next_sample(&mut self) -> i32 {
+        if N == 0 {
+            return 0;
+        }
+
+        // the top of phase * N is the table index and the bottom 32 bits the position between
+        // entries, of which the top 16 bits are plenty for interpolating
+        let position = self.phase as u64 * N as u64;
+        let index = (position >> 32) as usize;
+        let frac = ((position >> 16) & 0xFFFF) as i64;
+        self.phase = self.phase.wrapping_add(self.step);
+
+        let at = |offset: usize| self.table[(index + offset) % N] as i64;
+        let sample = match self.interpolation {
+            Interpolation::Nearest => at(0),
+            Interpolation::Linear => {
+                let (x1, x2) = (at(0), at(1));
+                x1 + (((x2 - x1) * frac) >> 16)
+            }
+            Interpolation::Cubic => {
+                let (x0, x1, x2, x3) = (at(N - 1), at(0), at(1), at(2));
+                // Catmull-Rom with every coefficient doubled, hence the final shift by 17
+                let a = 3 * (x1 - x2) + x3 - x0;
+                let b = 2 * x0 - 5 * x1 + 4 * x2 - x3;
+                let c = x2 - x0;
+                let ab = ((a * frac) >> 16) + b;
+                let abc = ((ab * frac) >> 16) + c;
+                x1 + ((abc * frac) >> 17)
+            }
+        };
+        sample as i32
+    }
+}
+
+/// # Purpose
+/// A streaming sine oscillator for tones that change while they play, e.g. a theremin. Each
+/// sample is evaluated with `sine_turns` from a phase accumulator, so there is no table to
+/// regenerate and `set_frequency` only changes the step: the phase carries on from where it was
+/// and the waveform bends to the new pitch without a jump.
+///
+/// The phase is a `u32` where the full range is one period, like `Wavetable`, and is handed to
+/// `sine_turns` as a fraction of a turn. Evaluating the series costs a handful of software float
+/// operations per sample, where a `Wavetable` costs a table read and a multiply, so at the higher
+/// rates the table is the one to reach for.
+pub struct SineOscillator {
+    phase: u32,
+    step: u32,
+    sample_rate: f32,
+    amplitude: i32,
+}
+
+impl SineOscillator {
+    /// # Purpose
+    /// An oscillator at `freq_hz` for output at `sample_rate`, peaking at `amplitude`, which is
+    /// clamped to the 24 bit range. It starts at phase 0, i.e. from silence.
+    pub fn new(freq_hz: f32, sample_rate: f32, amplitude: i32) -> Self {
+        let mut oscillator = SineOscillator {
+            phase: 0,
+            step: 0,
+            sample_rate,
+            amplitude: amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT),
+        };
+        oscillator.set_frequency(freq_hz);
+        oscillator
+    }
+
+    /// # Purpose
+    /// Sets the frequency from the next sample on. Only the step changes, never the phase, so
+    /// this is safe to call between any two samples.
+    pub fn set_frequency(&mut self, freq_hz: f32) {
+        self.step = (freq_hz / self.sample_rate * 4_294_967_296.0) as u32;
+    }
+
+    /// # Purpose
+    /// Returns the frequency being played, as the step quantizes it, in Hertz.
+    pub fn frequency(&self) -> f32 {
+        self.step as f32 / 4_294_967_296.0 * self.sample_rate
+    }
+
+    /// # Purpose
+    /// Follows the output to a new `sample_rate`, e.g. after `I2sOutput::set_sample_frequency`,
+    /// keeping the frequency and the phase.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let freq_hz = self.frequency();
+        self.sample_rate = sample_rate;
+        self.set_frequency(freq_hz);
+    }
+
+    /// # Purpose
+    /// Sets the peak, clamped to the 24 bit range, from the next sample on.
+    pub fn set_amplitude(&mut self, amplitude: i32) {
+        self.amplitude = amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT);
+    }
+
+    /// # Purpose
+    /// The next output sample, advancing the phase by one step.
+    pub fn next_sample(&mut self) -> i32 {
+        let turns = self.phase as f32 / 4_294_967_296.0;
+        self.phase = self.phase.wrapping_add(self.step);
+        (self.amplitude as f32 * sine_turns(turns)) as i32
+    }
+
+    /// # Purpose
+    /// The next output sample packed for the tx fifo, one per fifo write.
+    pub fn next_word(&mut self) -> u32 {
+        pack_sample(self.next_sample())
+    }
+}
+
+/// # Purpose
+/// How a `Chirp` moves from its start frequency to its end frequency.
+/// # Members
+/// - Linear:       the same number of Hertz every second, to look closely at a filter's edge
+/// - Logarithmic:  the same number of octaves every second, like `generate_chirp`
+#[derive(Clone, Copy, PartialEq)]
+pub enum SweepLaw {
+    Linear,
+    Logarithmic,
+}
+
+/// # Purpose
+/// A streaming sine sweep for frequency response measurements, e.g. watching the DAC's output
+/// filter on a scope. Unlike `generate_chirp` it is not limited to a table, so the sweep can take
+/// as long as `duration_s` needs, and it generates one sample per fifo write.
+///
+/// The sample path is a phase accumulator like `Wavetable`'s: a `u32` phase advanced by a step,
+/// so the phase never jumps. The exact frequency of the sweep is only computed every
+/// `CHIRP_BLOCK` samples, in f64, and the step slides linearly between those points, which keeps
+/// the cost per sample to integer adds and one `sine_turns`. At the end of the sweep the
+/// frequency goes back to the start and the sweep repeats, the phase still carrying on from where
+/// it was, so looping it has no click.
+pub struct Chirp {
+    start_hz: f32,
+    end_hz: f32,
+    sample_rate: f32,
+    law: SweepLaw,
+    amplitude: i32,
+    // samples in the whole sweep and the position in it
+    length: u32,
+    position: u32,
+    phase: u32,
+    // the phase step in 2^-48 of a period, so the slide between blocks keeps its small steps
+    step: u64,
+    slide: i64,
+}
+
+impl Chirp {
+    /// # Purpose
+    /// A sweep from `start_hz` to `end_hz` over `duration_s` seconds at `sample_rate`, peaking at
+    /// `amplitude`, which is clamped to the 24 bit range. Either direction works. A logarithmic
+    /// sweep can not start or end at 0Hz, so its frequencies are raised to at least 1Hz.
+    pub fn new(start_hz: f32, end_hz: f32, duration_s: f32, sample_rate: f32, law: SweepLaw, amplitude: i32) -> Self {
+        let (start_hz, end_hz) = match law {
+            SweepLaw::Linear => (start_hz.max(0.0), end_hz.max(0.0)),
+            SweepLaw::Logarithmic => (start_hz.max(1.0), end_hz.max(1.0)),
+        };
+        let mut chirp = Chirp {
+            start_hz,
+            end_hz,
+            sample_rate,
+            law,
+            amplitude: amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT),
+            length: ((duration_s * sample_rate) as u32).max(1),
+            position: 0,
+            phase: 0,
+            step: 0,
+            slide: 0,
+        };
+        chirp.step = chirp.step_at(0);
+        chirp
+    }
+
+    /// # Purpose
+    /// Returns the frequency the sweep is at, in Hertz.
+    pub fn frequency(&self) -> f32 {
+        (self.step >> 16) as f32 / 4_294_967_296.0 * self.sample_rate
+    }
+
+    /// # Purpose
+    /// Returns how far through the current sweep it is, from 0.0 to just under 1.0, e.g. to
+    /// trigger a scope at the start of each sweep.
+    pub fn progress(&self) -> f32 {
+        self.position as f32 / self.length as f32
+    }
+
+    /// # Purpose
+    /// The next output sample, advancing the sweep by one sample.
+    pub fn next_sample(&mut self) -> i32 {
+        if self.position.is_multiple_of(CHIRP_BLOCK) {
+            let block_end = (self.position + CHIRP_BLOCK).min(self.length);
+            let target = self.step_at(block_end) as i64;
+            self.slide = (target - self.step as i64) / (block_end - self.position) as i64;
+        }
+
+        let turns = self.phase as f32 / 4_294_967_296.0;
+        self.phase = self.phase.wrapping_add((self.step >> 16) as u32);
+        self.step = self.step.wrapping_add(self.slide as u64);
+        self.position += 1;
+        if self.position >= self.length {
+            self.position = 0;
+            self.step = self.step_at(0);
+        }
+        (self.amplitude as f32 * sine_turns(turns)) as i32
+    }
+
+    /// # Purpose
+    /// The next output sample packed for the tx fifo, one per fifo write.
+    pub fn next_word(&mut self) -> u32 {
+        pack_sample(self.next_sample())
+    }
+
+    /// # Purpose
+    /// The exact phase step, in 2^-48 of a period, `position` samples into the sweep.
+    fn step_at(&self, position: u32) -> u64 {
+        let t = position as f64 / self.length as f64;
+        let (start, end) = (self.start_hz as f64, self.end_hz as f64);
+        let freq_hz = match self.law {
+            SweepLaw::Linear => start + (end - start) * t,
+            SweepLaw::Logarithmic => start * exp(ln(end / start) * t),
+        };
+        (freq_hz / self.sample_rate as f64 * 281_474_976_710_656.0) as u64
+    }
+}
+
+/// # Purpose
+/// Fills an interleaved (left, right) buffer with a different waveform on each channel, e.g. a sine
+/// on the left and a square on the right, so it is obvious by ear which speaker is which.
+/// `freqs` is the (left, right) frequency pair in Hertz and both channels use `amplitude`, clamped
+/// to the 24 bit range as in `fill_waveform`. A trailing odd word, which would be half a frame, is
+/// left untouched.
+pub fn generate_stereo_mixed(
+    samples: &mut [u32],
+    left_wave: Waveform,
+    right_wave: Waveform,
+    freqs: (f32, f32),
+    sample_rate: f32,
+    amplitude: i32,
+) {
+    let (left_freq, right_freq) = freqs;
+    let amplitude = amplitude.clamp(-FULL_SCALE_24BIT, FULL_SCALE_24BIT);
+    for (i, frame) in samples.chunks_exact_mut(2).enumerate() {
+        let t = i as f32 / sample_rate;
+        let left_phase = left_freq * t - (left_freq * t) as u32 as f32;
+        let right_phase = right_freq * t - (right_freq * t) as u32 as f32;
+        frame[0] = pack_sample((amplitude as f32 * left_wave.value(left_phase)) as i32);
+        frame[1] = pack_sample((amplitude as f32 * right_wave.value(right_phase)) as i32);
+    }
+}
+
+/// # Purpose
+/// Fills `samples` with a "walking ones" debug pattern: every word has exactly one bit set and the
+/// set bit moves up one position each frame (a left and right word pair), wrapping after bit 31.
+///
+/// This bypasses the normal audio path entirely, there is no `AMPLITUDE` scaling, no
+/// `cast_to_u32_as_i32` and no `bit_reverse`. The words are raw fifo codes, so on a logic analyzer
+/// the BCK edge that carries the high bit in frame `n` is the edge the hardware shifts bit
+/// `n % 32` of a fifo word out on. That pins down MSB/LSB order and justification empirically.
+pub fn generate_walking_ones(samples: &mut [u32]) {
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let frame = i / 2;
+        *sample = 1 << (frame % 32);
+    }
+}
@@ -0,0 +1,459 @@
+//! A USB Audio Class 2.0 speaker that streams host audio out through the I2S output.
+
+use rp2040_hal::pio::PIOExt;
+use usb_device::class_prelude::*;
+
+use crate::dsp::DriftResampler;
+use crate::error::I2sError;
+use crate::i2s::{iso_max_packet_size, I2sOutput, BYTES_PER_FRAME};
+use crate::rates::SampleFrequency;
+use crate::sample::{le_pcm_to_wire, pack_sample, unpack_sample, CHANNELS};
+
+// Largest packet a full speed isochronous endpoint can carry
+pub const MAX_ISO_PACKET_SIZE: usize = 1023;
+// Words of decoded audio held between the USB packets and the tx fifo, about 21ms of stereo at
+// 48khz. A power of two so the free running ring positions stay valid across wrapping.
+pub const RING_WORDS: usize = 2048;
+// Frames the drift correction keeps in the ring, half of it, so there is as much room for the
+// host running fast as there is audio in hand for it running slow
+pub const TARGET_FILL_FRAMES: usize = RING_WORDS / CHANNELS / 2;
+
+// Class codes and descriptor subtypes from the USB Audio Device Class 2.0 specification
+const AUDIO: u8 = 0x01;
+const FUNCTION_SUBCLASS_UNDEFINED: u8 = 0x00;
+const AUDIOCONTROL: u8 = 0x01;
+const AUDIOSTREAMING: u8 = 0x02;
+const IP_VERSION_02_00: u8 = 0x20;
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const AC_HEADER: u8 = 0x01;
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+const AC_CLOCK_SOURCE: u8 = 0x0A;
+const AS_GENERAL: u8 = 0x01;
+const AS_FORMAT_TYPE: u8 = 0x02;
+const FORMAT_TYPE_I: u8 = 0x01;
+const EP_GENERAL: u8 = 0x01;
+// Class specific requests and the clock source control selectors they are used with
+const REQUEST_CUR: u8 = 0x01;
+const REQUEST_RANGE: u8 = 0x02;
+const CS_SAM_FREQ_CONTROL: u8 = 0x01;
+const CS_CLOCK_VALID_CONTROL: u8 = 0x02;
+// Entity ids of the audio function: USB streaming input terminal -> speaker output terminal, both
+// running from one programmable clock source
+const INPUT_TERMINAL_ID: u8 = 0x01;
+const OUTPUT_TERMINAL_ID: u8 = 0x03;
+const CLOCK_SOURCE_ID: u8 = 0x04;
+// Length of the class specific AudioControl descriptors: header, clock source and both terminals
+const AC_TOTAL_LENGTH: u16 = 9 + 8 + 17 + 12;
+
+/// # Purpose
+/// A `usb-device` class that makes the Pico enumerate as a 24 bit stereo USB speaker and decodes
+/// what the host streams into packed tx fifo words.
+///
+/// Received isochronous packets are decoded with `le_pcm_to_wire` into a ring of `RING_WORDS`
+/// words, whole frames at a time, and `drain_into` moves them on to the tx fifo. Call
+/// `UsbDevice::poll` with the speaker and then `drain_into` from the same loop, often enough that
+/// neither the 1ms packets nor the fifo are kept waiting.
+///
+/// The host picks the rate from the `SampleFrequency` presets whose packets fit a full speed
+/// endpoint, at most `MAX_ISO_PACKET_SIZE` bytes per 1ms frame, which is 24 bit stereo up to 96khz.
+/// A rate change only reaches the ring, so the application has to pass it on to the `I2sOutput`
+/// it feeds: poll `take_rate_change` and hand the new rate to `I2sOutput::set_sample_frequency`.
+///
+/// The host sends at the rate of its SOF clock while BCK comes from the system clock, so the two
+/// drift apart by the crystal tolerances and the divisor quantization
+/// (`frequency_accuracy_report`). The endpoint is declared adaptive and, by default, `drain_into`
+/// plays the ring through a `DriftResampler`, which reads it a few ppm faster or slower to keep
+/// it half full, `TARGET_FILL_FRAMES`. `buffered_frames` and `resample_ratio` show how well that
+/// tracks. Playback starts, and restarts after the ring ran dry, once the ring has filled to the
+/// target. With `set_drift_correction(false)` the words go out bit exact, and the ring only soaks
+/// up the drift for a while, after which frames are dropped once it is full (see
+/// `take_dropped_frames`) or silence is played once it runs dry.
+pub struct Uac2Speaker<'a, B: UsbBus> {
+    audio_control: InterfaceNumber,
+    audio_streaming: InterfaceNumber,
+    out_ep: EndpointOut<'a, B>,
+    sample_rate: u32,
+    // set by the host, until the application picks it up
+    rate_change: Option<SampleFrequency>,
+    // alternate setting of the streaming interface, 1 while the host streams
+    alt_setting: u8,
+    ring: [u32; RING_WORDS],
+    // free running read and write positions in `ring`, reduced modulo `RING_WORDS` on use
+    read: usize,
+    write: usize,
+    dropped_frames: u32,
+    // `None` while drift correction is off
+    resampler: Option<DriftResampler>,
+    // the right word of a resampled frame, waiting for room in the fifo
+    pending: Option<u32>,
+    // the resampled output waits for the ring to fill to the target before it starts
+    filling: bool,
+}
+
+impl<'a, B: UsbBus> Uac2Speaker<'a, B> {
+    /// # Purpose
+    /// Allocates the interfaces and the isochronous OUT endpoint of a speaker starting out at
+    /// `sample_frequency`, the rate the `I2sOutput` it feeds runs at. Fails with
+    /// `I2sError::PacketTooLarge` if its packets would not fit a full speed isochronous endpoint.
+    pub fn new(alloc: &'a UsbBusAllocator<B>, sample_frequency: SampleFrequency) -> Result<Self, I2sError> {
+        let sample_rate = sample_frequency.as_hz();
+        let bytes = iso_max_packet_size(sample_rate);
+        if bytes > MAX_ISO_PACKET_SIZE {
+            return Err(I2sError::PacketTooLarge { bytes });
+        }
+        // the endpoint has to take packets of the fastest rate the host may switch to
+        let bytes = supported_rates().map(|rate| iso_max_packet_size(rate.as_hz())).max().unwrap_or(bytes);
+
+        Ok(Uac2Speaker {
+            audio_control: alloc.interface(),
+            audio_streaming: alloc.interface(),
+            out_ep: alloc.isochronous(
+                IsochronousSynchronizationType::Adaptive,
+                IsochronousUsageType::Data,
+                bytes as u16,
+                1,
+            ),
+            sample_rate,
+            rate_change: None,
+            alt_setting: 0,
+            ring: [0; RING_WORDS],
+            read: 0,
+            write: 0,
+            dropped_frames: 0,
+            resampler: Some(DriftResampler::new(TARGET_FILL_FRAMES)),
+            pending: None,
+            filling: true,
+        })
+    }
+
+    /// # Purpose
+    /// Returns true while the host has the streaming interface open.
+    pub fn is_streaming(&self) -> bool {
+        self.alt_setting == 1
+    }
+
+    /// # Purpose
+    /// Returns the rate the host last switched the speaker to, if it changed since the last call.
+    /// Pass it on to `I2sOutput::set_sample_frequency` so the output follows.
+    pub fn take_rate_change(&mut self) -> Option<SampleFrequency> {
+        self.rate_change.take()
+    }
+
+    /// # Purpose
+    /// The number of whole frames waiting in the ring.
+    pub fn buffered_frames(&self) -> usize {
+        self.write.wrapping_sub(self.read) / CHANNELS
+    }
+
+    /// # Purpose
+    /// Returns the next packed word for the tx fifo, or `None` if the ring is empty.
+    pub fn next_word(&mut self) -> Option<u32> {
+        if self.read == self.write {
+            return None;
+        }
+        let word = self.ring[self.read % RING_WORDS];
+        self.read = self.read.wrapping_add(1);
+        Some(word)
+    }
+
+    /// # Purpose
+    /// Turns the drift correction on or off, see the notes on `Uac2Speaker`. It is on from `new`.
+    /// Either way the ring starts over from its current fill.
+    pub fn set_drift_correction(&mut self, enabled: bool) {
+        self.resampler = enabled.then(|| DriftResampler::new(TARGET_FILL_FRAMES));
+        self.filling = true;
+    }
+
+    /// # Purpose
+    /// Returns the ratio of frames read from the ring to frames played, above 1.0 while the host
+    /// runs fast, or `None` while drift correction is off.
+    pub fn resample_ratio(&self) -> Option<f32> {
+        self.resampler.as_ref().map(DriftResampler::ratio)
+    }
+
+    /// # Purpose
+    /// Returns the next frame from the ring as two 24 bit samples, or `None` if it holds no whole
+    /// frame.
+    fn next_frame(&mut self) -> Option<[i32; 2]> {
+        if self.write.wrapping_sub(self.read) < CHANNELS {
+            return None;
+        }
+        let left = self.next_word().map(unpack_sample)?;
+        let right = self.next_word().map(unpack_sample)?;
+        Some([left, right])
+    }
+
+    /// # Purpose
+    /// Drops whatever is buffered, e.g. when it no longer matches the rate, and has the resampled
+    /// output wait for the ring to fill again.
+    fn flush(&mut self) {
+        self.read = self.write;
+        self.pending = None;
+        self.filling = true;
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+    }
+
+    /// # Purpose
+    /// Moves buffered audio into the tx fifo of `i2s` until the fifo is full or the ring is empty,
+    /// without ever waiting. With drift correction on the frames are resampled on the way.
+    pub fn drain_into<P: PIOExt>(&mut self, i2s: &mut I2sOutput<P>) {
+        let bit_order = i2s.bit_order();
+        if let Some(word) = self.pending {
+            if i2s.is_full() {
+                return;
+            }
+            i2s.write_sample(word);
+            self.pending = None;
+        }
+
+        let Some(mut resampler) = self.resampler.take() else {
+            while !i2s.is_full() {
+                match self.next_word() {
+                    Some(word) => i2s.write_sample(bit_order.from_reversed(word)),
+                    None => break,
+                }
+            }
+            return;
+        };
+
+        if self.filling && self.buffered_frames() >= TARGET_FILL_FRAMES {
+            self.filling = false;
+        }
+        while !self.filling && !i2s.is_full() {
+            match resampler.next_frame(|| self.next_frame()) {
+                Some([left, right]) => {
+                    i2s.write_sample(bit_order.pack(left));
+                    let right = bit_order.pack(right);
+                    if i2s.is_full() {
+                        self.pending = Some(right);
+                    } else {
+                        i2s.write_sample(right);
+                    }
+                }
+                None => {
+                    // ran dry, start again from a half full ring rather than frame by frame
+                    resampler.reset();
+                    self.filling = true;
+                }
+            }
+        }
+        self.resampler = Some(resampler);
+    }
+
+    /// # Purpose
+    /// Returns the number of received frames dropped because the ring was full since the last
+    /// call, and clears it.
+    pub fn take_dropped_frames(&mut self) -> u32 {
+        let dropped = self.dropped_frames;
+        self.dropped_frames = 0;
+        dropped
+    }
+
+    /// # Purpose
+    /// Returns true if `request` is a class request to this speaker's AudioControl interface.
+    fn is_audio_control_request(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index as u8 == u8::from(self.audio_control)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for Uac2Speaker<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        let [total_lo, total_hi] = AC_TOTAL_LENGTH.to_le_bytes();
+        // front left and front right
+        let channel_config = [0x03, 0x00, 0x00, 0x00];
+
+        writer.iad(self.audio_control, 2, AUDIO, FUNCTION_SUBCLASS_UNDEFINED, IP_VERSION_02_00)?;
+
+        // AudioControl interface: clock source -> USB streaming input terminal -> speaker
+        writer.interface(self.audio_control, AUDIO, AUDIOCONTROL, IP_VERSION_02_00)?;
+        // bcdADC 2.00, desktop speaker category, no controls
+        writer.write(CS_INTERFACE, &[AC_HEADER, 0x00, 0x02, 0x01, total_lo, total_hi, 0x00])?;
+        // internal programmable clock, the host sets the sample rate and reads its validity
+        writer.write(CS_INTERFACE, &[AC_CLOCK_SOURCE, CLOCK_SOURCE_ID, 0x03, 0x07, 0x00, 0x00])?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                AC_INPUT_TERMINAL,
+                INPUT_TERMINAL_ID,
+                0x01, 0x01, // USB streaming
+                0x00,
+                CLOCK_SOURCE_ID,
+                CHANNELS as u8,
+                channel_config[0], channel_config[1], channel_config[2], channel_config[3],
+                0x00,
+                0x00, 0x00,
+                0x00,
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                AC_OUTPUT_TERMINAL,
+                OUTPUT_TERMINAL_ID,
+                0x01, 0x03, // speaker
+                0x00,
+                INPUT_TERMINAL_ID,
+                CLOCK_SOURCE_ID,
+                0x00, 0x00,
+                0x00,
+            ],
+        )?;
+
+        // AudioStreaming interface: alternate setting 0 is idle, 1 carries the stream
+        writer.interface_alt(self.audio_streaming, 0, AUDIO, AUDIOSTREAMING, IP_VERSION_02_00, None)?;
+        writer.interface_alt(self.audio_streaming, 1, AUDIO, AUDIOSTREAMING, IP_VERSION_02_00, None)?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                AS_GENERAL,
+                INPUT_TERMINAL_ID,
+                0x00,
+                FORMAT_TYPE_I,
+                0x01, 0x00, 0x00, 0x00, // PCM
+                CHANNELS as u8,
+                channel_config[0], channel_config[1], channel_config[2], channel_config[3],
+                0x00,
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[AS_FORMAT_TYPE, FORMAT_TYPE_I, (BYTES_PER_FRAME / CHANNELS) as u8, 24],
+        )?;
+        writer.endpoint(&self.out_ep)?;
+        writer.write(CS_ENDPOINT, &[EP_GENERAL, 0x00, 0x00, 0x00, 0x00, 0x00])
+    }
+
+    fn reset(&mut self) {
+        self.alt_setting = 0;
+        self.flush();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = *xfer.request();
+        if !self.is_audio_control_request(&request) {
+            return;
+        }
+
+        let entity = (request.index >> 8) as u8;
+        let selector = (request.value >> 8) as u8;
+        if entity != CLOCK_SOURCE_ID {
+            let _ = xfer.reject();
+            return;
+        }
+
+        let rate = self.sample_rate.to_le_bytes();
+        let _ = match (request.request, selector) {
+            (REQUEST_CUR, CS_SAM_FREQ_CONTROL) => xfer.accept_with(&rate),
+            (REQUEST_RANGE, CS_SAM_FREQ_CONTROL) => {
+                // the count, then one min, max and resolution triple per discrete rate
+                let mut ranges = [0u8; 2 + 12 * SampleFrequency::COUNT];
+                let mut count = 0u16;
+                for supported in supported_rates() {
+                    let hz = supported.as_hz().to_le_bytes();
+                    let start = 2 + 12 * count as usize;
+                    ranges[start..start + 4].copy_from_slice(&hz);
+                    ranges[start + 4..start + 8].copy_from_slice(&hz);
+                    count += 1;
+                }
+                ranges[0..2].copy_from_slice(&count.to_le_bytes());
+                xfer.accept_with(&ranges[..2 + 12 * count as usize])
+            }
+            (REQUEST_CUR, CS_CLOCK_VALID_CONTROL) => xfer.accept_with(&[1]),
+            _ => xfer.reject(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = *xfer.request();
+        if !self.is_audio_control_request(&request) {
+            return;
+        }
+
+        let entity = (request.index >> 8) as u8;
+        let selector = (request.value >> 8) as u8;
+        if entity != CLOCK_SOURCE_ID || request.request != REQUEST_CUR || selector != CS_SAM_FREQ_CONTROL {
+            let _ = xfer.reject();
+            return;
+        }
+
+        let requested = match *xfer.data() {
+            [b0, b1, b2, b3] => SampleFrequency::from_hz(u32::from_le_bytes([b0, b1, b2, b3])),
+            _ => None,
+        };
+        match requested.filter(|rate| supported_rates().any(|supported| supported == *rate)) {
+            Some(rate) => {
+                if rate.as_hz() != self.sample_rate {
+                    self.sample_rate = rate.as_hz();
+                    self.rate_change = Some(rate);
+                    // what is buffered was decoded for the old rate
+                    self.flush();
+                }
+                let _ = xfer.accept();
+            }
+            None => {
+                let _ = xfer.reject();
+            }
+        }
+    }
+
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        if interface == self.audio_streaming {
+            Some(self.alt_setting)
+        } else {
+            None
+        }
+    }
+
+    fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
+        if interface != self.audio_streaming || alternative > 1 {
+            return false;
+        }
+        self.alt_setting = alternative;
+        true
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.out_ep.address() {
+            return;
+        }
+
+        let mut packet = [0u8; MAX_ISO_PACKET_SIZE];
+        let len = match self.out_ep.read(&mut packet) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+
+        // whole frames only, so the ring always holds a left word followed by its right word
+        for frame in packet[..len].chunks_exact(BYTES_PER_FRAME) {
+            if RING_WORDS - self.write.wrapping_sub(self.read) < CHANNELS {
+                self.dropped_frames = self.dropped_frames.saturating_add(1);
+                continue;
+            }
+            for sample in frame.chunks_exact(BYTES_PER_FRAME / CHANNELS) {
+                self.ring[self.write % RING_WORDS] = le_pcm_to_wire(sample).unwrap_or_else(|| pack_sample(0));
+                self.write = self.write.wrapping_add(1);
+            }
+        }
+
+        // once per packet, so the control loop sees the fill at a steady 1ms, not while
+        // playback waits for the ring to fill
+        let fill = self.buffered_frames();
+        if let (Some(resampler), false) = (self.resampler.as_mut(), self.filling) {
+            resampler.update(fill);
+        }
+    }
+}
+
+/// # Purpose
+/// The `SampleFrequency` presets a full speed isochronous endpoint can carry, slowest first.
+fn supported_rates() -> impl Iterator<Item = SampleFrequency> {
+    SampleFrequency::ALL
+        .into_iter()
+        .filter(|rate| iso_max_packet_size(rate.as_hz()) <= MAX_ISO_PACKET_SIZE)
+}
@@ -0,0 +1,169 @@
+//! A minimal reader for PCM WAV clips embedded in flash with `include_bytes!`.
+
+use crate::error::WavError;
+use crate::rates::SampleFrequency;
+use crate::sample::le_pcm_to_sample;
+
+// WAVE_FORMAT_PCM, plain integer samples
+const FORMAT_PCM: u16 = 1;
+// the layouts `WavClip` plays: mono or stereo, 16, 24 or 32 bit
+const MAX_CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: [u16; 3] = [16, 24, 32];
+// the RIFF header: "RIFF", the file size and "WAVE"
+const HEADER_LEN: usize = 12;
+// the id and size in front of every chunk
+const CHUNK_HEADER_LEN: usize = 8;
+// format tag, channels, sample rate, byte rate, block align and bits per sample
+const FMT_LEN: usize = 16;
+
+/// # Purpose
+/// A mono or stereo PCM WAV clip of 16, 24 or 32 bit samples, recorded at one of the
+/// `SampleFrequency` presets. Only borrows the file, so a clip in flash is played straight from
+/// flash.
+///
+/// # Use
+/// ```ignore
+/// static CLIP: &[u8] = include_bytes!("clip.wav");
+/// let clip = WavClip::parse(CLIP)?;
+/// // switches the output to the clip's rate first if it runs at another
+/// i2s.play_wav(&clip)?;
+/// ```
+#[derive(Clone, Copy)]
+pub struct WavClip<'a> {
+    // the body of the `data` chunk, trimmed to whole frames
+    data: &'a [u8],
+    channels: u16,
+    bits: u16,
+    sample_frequency: SampleFrequency,
+}
+
+impl<'a> WavClip<'a> {
+    /// # Purpose
+    /// Walks the chunks of the WAV file in `bytes`, reads its rate, channel count and bit depth
+    /// from the `fmt ` chunk and checks it can be played as it is: integer PCM, mono or stereo,
+    /// 16, 24 or 32 bit and at exactly the rate of a `SampleFrequency` preset. Chunks other than
+    /// `fmt ` and `data`, such as `LIST` metadata, are skipped. Fails with the first reason it can
+    /// not be played, see `WavError`, as there is no resampling.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, WavError> {
+        if bytes.len() < HEADER_LEN || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(WavError::NotWave);
+        }
+
+        let mut fmt = None;
+        let mut data = None;
+        let mut rest = &bytes[HEADER_LEN..];
+        while rest.len() >= CHUNK_HEADER_LEN {
+            let id = [rest[0], rest[1], rest[2], rest[3]];
+            let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            let body = rest[CHUNK_HEADER_LEN..].get(..len).ok_or(WavError::Truncated)?;
+            match &id {
+                b"fmt " => fmt = Some(body),
+                b"data" => data = Some(body),
+                _ => {}
+            }
+            // chunks are padded to an even length, the pad byte is not counted in `len`
+            let padded = (CHUNK_HEADER_LEN + len + (len & 1)).min(rest.len());
+            rest = &rest[padded..];
+        }
+
+        let fmt = fmt.ok_or(WavError::MissingChunk { id: *b"fmt " })?;
+        let data = data.ok_or(WavError::MissingChunk { id: *b"data" })?;
+        if fmt.len() < FMT_LEN {
+            return Err(WavError::Truncated);
+        }
+        let format_tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+        let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+        let wav_hz = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+        let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+
+        if format_tag != FORMAT_PCM {
+            return Err(WavError::UnsupportedEncoding { format_tag });
+        }
+        if channels == 0 || channels > MAX_CHANNELS {
+            return Err(WavError::UnsupportedChannels { channels });
+        }
+        if !BITS_PER_SAMPLE.contains(&bits) {
+            return Err(WavError::UnsupportedBitDepth { bits });
+        }
+        let sample_frequency = SampleFrequency::from_hz(wav_hz).ok_or(WavError::UnsupportedRate { wav_hz })?;
+
+        let frame_len = channels as usize * bits as usize / 8;
+        let whole = data.len() - data.len() % frame_len;
+        Ok(WavClip { data: &data[..whole], channels, bits, sample_frequency })
+    }
+
+    /// # Purpose
+    /// Returns the preset the clip was recorded at, the rate `I2sOutput::play_wav` plays it at.
+    pub fn sample_frequency(&self) -> SampleFrequency {
+        self.sample_frequency
+    }
+
+    /// # Purpose
+    /// Returns 1 for a mono clip and 2 for a stereo one.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// # Purpose
+    /// Returns the bits per sample as stored in the file, before decoding to 24 bit.
+    pub fn bits_per_sample(&self) -> u16 {
+        self.bits
+    }
+
+    /// # Purpose
+    /// Returns the number of frames in the clip.
+    pub fn len(&self) -> usize {
+        self.data.len() / self.frame_len()
+    }
+
+    /// # Purpose
+    /// Returns true if the `data` chunk holds no whole frame.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// # Purpose
+    /// The frames of the clip once through as (left, right) pairs, decoded to the 24 bit sample
+    /// range with `le_pcm_to_sample`, ready for `pack_sample` or `I2sOutput::write_stereo`. A mono
+    /// clip has its sample on both channels.
+    pub fn frames(&self) -> WavFrames<'a> {
+        WavFrames {
+            chunks: self.data.chunks_exact(self.frame_len()),
+            sample_len: self.bits as usize / 8,
+        }
+    }
+
+    /// # Purpose
+    /// Bytes per frame, one sample per channel.
+    fn frame_len(&self) -> usize {
+        self.channels as usize * self.bits as usize / 8
+    }
+}
+
+/// # Purpose
+/// The frames of a `WavClip` in order, see `WavClip::frames`. Chain it with `cycle` to loop a
+/// clip without a gap.
+#[derive(Clone)]
+pub struct WavFrames<'a> {
+    chunks: core::slice::ChunksExact<'a, u8>,
+    sample_len: usize,
+}
+
+impl Iterator for WavFrames<'_> {
+    type Item = (i32, i32);
+
+    fn next(&mut self) -> Option<(i32, i32)> {
+        // every chunk is one or two samples of 2, 3 or 4 bytes, which always decode
+        let frame = self.chunks.next()?;
+        let left = le_pcm_to_sample(&frame[..self.sample_len])?;
+        let right = match frame.get(self.sample_len..) {
+            Some(right) if !right.is_empty() => le_pcm_to_sample(right)?,
+            _ => left,
+        };
+        Some((left, right))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}